@@ -1,27 +1,111 @@
-use kdl::{KdlDocument, KdlNode};
+use std::collections::HashSet;
+
+use miette::{LabeledSpan, SourceSpan};
 use toposort::Dag;
 
-fn child_nodes(doc_node: &KdlNode) -> &[KdlNode] {
-    doc_node
-        .children()
-        .map(KdlDocument::nodes)
-        .unwrap_or_default()
-}
+use crate::{Dependencies, Dependency};
 
-fn traverse(node: &KdlNode, dag: &mut Dag<String>) {
-    for child in child_nodes(node).iter() {
-        let name = child.name().to_string();
-        dag.before(name, node.name().to_string());
+fn traverse(node: &Dependency, dag: &mut Dag<String>) {
+    for child in node.dependencies.iter() {
+        dag.before(child.name.clone(), node.name.clone());
         traverse(child, dag)
     }
 }
 
-pub fn parse_deps(dependencies_node: &KdlNode) -> Dag<String> {
-    let mut dag = Dag::new();
-    for child in child_nodes(dependencies_node) {
-        traverse(child, &mut dag);
+pub fn add_deps(dag: &mut Dag<String>, dependencies: &Dependencies) {
+    for child in dependencies.dependers.iter() {
+        traverse(child, dag);
+    }
+}
+
+/// Span of the node name alone, because node spans also cover the children block.
+pub fn name_span(input: &str, node: &Dependency) -> SourceSpan {
+    let start = node.span.0;
+    let quoted = input[start..].starts_with('"');
+    let len = if quoted {
+        node.name.len() + 2
+    } else {
+        node.name.len()
+    };
+    (start, len).into()
+}
+
+fn collect_edge_labels(
+    input: &str,
+    node: &Dependency,
+    edges: &HashSet<(&str, &str)>,
+    labels: &mut Vec<LabeledSpan>,
+) {
+    for child in node.dependencies.iter() {
+        if edges.contains(&(node.name.as_str(), child.name.as_str())) {
+            let label = format!("`{}` depends on `{}`", node.name, child.name);
+            labels.push(LabeledSpan::new_with_span(
+                Some(label),
+                name_span(input, child),
+            ));
+        }
+        collect_edge_labels(input, child, edges, labels)
+    }
+}
+
+fn collect_unknown<'a>(
+    node: &'a Dependency,
+    known: &HashSet<String>,
+    unknown: &mut Vec<&'a Dependency>,
+) {
+    if !known.contains(&node.name) {
+        unknown.push(node);
+    }
+    for child in node.dependencies.iter() {
+        collect_unknown(child, known, unknown)
+    }
+}
+
+/// Every node in `dependencies` whose name is not in `known`.
+pub fn unknown_images<'a>(
+    dependencies: &'a Dependencies,
+    known: &HashSet<String>,
+) -> Vec<&'a Dependency> {
+    let mut unknown = vec![];
+    for child in dependencies.dependers.iter() {
+        collect_unknown(child, known, &mut unknown);
+    }
+    unknown
+}
+
+fn collect_edges<'a>(node: &'a Dependency, edges: &mut Vec<(&'a Dependency, &'a Dependency)>) {
+    for child in node.dependencies.iter() {
+        edges.push((node, child));
+        collect_edges(child, edges)
+    }
+}
+
+/// Every (depender, dependency) pair declared in `dependencies`.
+pub fn edges(dependencies: &Dependencies) -> Vec<(&Dependency, &Dependency)> {
+    let mut edges = vec![];
+    for child in dependencies.dependers.iter() {
+        collect_edges(child, &mut edges);
+    }
+    edges
+}
+
+/// Labels every declaration of an edge that is part of `cycle`, where each
+/// image in `cycle` depends on the next one.
+pub fn cycle_labels(
+    input: &str,
+    dependencies: &Dependencies,
+    cycle: &[String],
+) -> Vec<LabeledSpan> {
+    let edges: HashSet<(&str, &str)> = cycle
+        .windows(2)
+        .map(|pair| (pair[0].as_str(), pair[1].as_str()))
+        .collect();
+
+    let mut labels = vec![];
+    for child in dependencies.dependers.iter() {
+        collect_edge_labels(input, child, &edges, &mut labels);
     }
-    dag
+    labels
 }
 
 #[cfg(test)]
@@ -29,12 +113,22 @@ mod tests {
     use crate::{parse::parse, IkkiConfigError};
 
     use super::*;
+    use crate::DependencyCondition;
+    use knuffel::span::Span;
     use toposort::Toposort;
 
+    #[derive(knuffel::Decode)]
+    #[knuffel(span_type=Span)]
+    struct DependenciesDocument {
+        #[knuffel(child)]
+        dependencies: Dependencies,
+    }
+
     fn parse_deps_from_string(input: &str) -> Result<Dag<String>, IkkiConfigError> {
-        let doc: KdlDocument = input.parse().expect("failed to parse");
-        let dependencies = doc.get("dependencies").expect("no dependencies");
-        Ok(parse_deps(dependencies))
+        let doc = knuffel::parse::<DependenciesDocument>("dependencies.kdl", input)?;
+        let mut dag = Dag::new();
+        add_deps(&mut dag, &doc.dependencies);
+        Ok(dag)
     }
 
     #[test]
@@ -43,7 +137,7 @@ mod tests {
         let order = parse_deps_from_string(input).unwrap();
         let order = order.toposort();
 
-        assert!(order.is_some());
+        assert!(order.is_ok());
 
         let mut expected = vec![
             vec!["grape", "mango", "melon", "tangerine", "papaiya"],
@@ -76,4 +170,305 @@ mod tests {
 
         assert_eq!(order.build_order(), expected);
     }
+
+    #[test]
+    fn images_absent_from_dependencies() {
+        let input = r#"
+            images {
+                image "api"
+                image "protobuf"
+                image "redis"
+            }
+            dependencies {
+                api {
+                    protobuf
+                }
+            }
+        "#;
+        let config = parse("isolated.kdl", input).unwrap();
+
+        let mut order = config.build_order();
+        for suborder in order.iter_mut() {
+            suborder.sort();
+        }
+
+        let expected = vec![
+            vec!["protobuf".to_string(), "redis".to_string()],
+            vec!["api".to_string()],
+        ];
+
+        assert_eq!(order, expected);
+    }
+
+    #[test]
+    fn dependers_of_image() {
+        let input = r#"
+            images {
+                image "api"
+                image "protobuf"
+                image "db"
+                image "cli"
+            }
+            dependencies {
+                api {
+                    protobuf
+                    db
+                }
+                cli {
+                    protobuf
+                }
+            }
+        "#;
+        let config = parse("deps.kdl", input).unwrap();
+
+        let order = config.dependers_of("db");
+        let expected = vec![vec!["db".to_string()], vec!["api".to_string()]];
+
+        assert_eq!(order, expected);
+    }
+
+    #[test]
+    fn dependers_of_image_within_target() {
+        let input = r#"
+            images {
+                image "api"
+                image "protobuf"
+                image "db"
+                image "cli"
+            }
+            dependencies {
+                api {
+                    protobuf
+                    db
+                }
+                cli {
+                    protobuf
+                }
+            }
+        "#;
+        let config = parse("deps.kdl", input).unwrap();
+
+        let order = config
+            .dependers_graph_within("protobuf", "protobuf")
+            .toposort()
+            .unwrap();
+        assert_eq!(order, vec![vec!["protobuf".to_string()]]);
+
+        let order = config
+            .dependers_graph_within("protobuf", "api")
+            .toposort()
+            .unwrap();
+        let expected = vec![vec!["protobuf".to_string()], vec!["api".to_string()]];
+        assert_eq!(order, expected);
+    }
+
+    #[test]
+    fn dependencies_of_image() {
+        let input = r#"
+            images {
+                image "api"
+                image "protobuf"
+                image "db"
+                image "cli"
+            }
+            dependencies {
+                api {
+                    protobuf
+                    db
+                }
+                cli {
+                    protobuf
+                }
+            }
+        "#;
+        let config = parse("deps.kdl", input).unwrap();
+
+        let mut order = config.dependencies_of("api");
+        for suborder in order.iter_mut() {
+            suborder.sort();
+        }
+
+        let expected = vec![
+            vec!["db".to_string(), "protobuf".to_string()],
+            vec!["api".to_string()],
+        ];
+
+        assert_eq!(order, expected);
+    }
+
+    #[test]
+    fn build_and_run_dependencies() {
+        let input = r#"
+            images {
+                image "api"
+                image "protobuf"
+                image "redis"
+            }
+            build-deps {
+                api {
+                    protobuf
+                }
+            }
+            run-deps {
+                api {
+                    redis
+                }
+            }
+        "#;
+        let config = parse("deps.kdl", input).unwrap();
+
+        let mut order = config.build_order();
+        for suborder in order.iter_mut() {
+            suborder.sort();
+        }
+        let expected = vec![
+            vec!["protobuf".to_string(), "redis".to_string()],
+            vec!["api".to_string()],
+        ];
+        assert_eq!(order, expected);
+
+        let mut order = config.start_order();
+        for suborder in order.iter_mut() {
+            suborder.sort();
+        }
+        let expected = vec![
+            vec!["protobuf".to_string(), "redis".to_string()],
+            vec!["api".to_string()],
+        ];
+        assert_eq!(order, expected);
+
+        // `api` is only rebuilt for `protobuf`, but restarted for both
+        assert_eq!(
+            config.dependers_of("redis"),
+            vec![vec!["redis".to_string()]]
+        );
+        assert_eq!(
+            config.restart_order("redis"),
+            vec![vec!["redis".to_string()], vec!["api".to_string()]]
+        );
+
+        let mut order = config.restart_order("protobuf");
+        for suborder in order.iter_mut() {
+            suborder.sort();
+        }
+        assert_eq!(order, vec![vec!["api".to_string(), "protobuf".to_string()]]);
+    }
+
+    #[test]
+    fn run_dependency_conditions() {
+        let input = r#"
+            images {
+                image "api" {
+                    service
+                }
+                image "db" {
+                    service {
+                        healthcheck {
+                            test "pg_isready"
+                        }
+                    }
+                }
+                image "migrations" {
+                    service
+                }
+            }
+            run-deps {
+                api {
+                    db condition="healthy"
+                    migrations condition="completed-successfully"
+                }
+            }
+        "#;
+        let config = parse("conditions.kdl", input).unwrap();
+
+        let expected = vec![
+            ("db".to_string(), DependencyCondition::Healthy),
+            (
+                "migrations".to_string(),
+                DependencyCondition::CompletedSuccessfully,
+            ),
+        ];
+        assert_eq!(config.start_conditions("api"), expected);
+        assert!(config.start_conditions("db").is_empty());
+
+        let input = r#"
+            images {
+                image "api" {
+                    service
+                }
+                image "db" {
+                    service
+                }
+            }
+            run-deps {
+                api {
+                    db condition="healthy"
+                }
+            }
+        "#;
+        match parse("conditions.kdl", input) {
+            Err(IkkiConfigError::InvalidConfiguration(err)) => {
+                assert!(err.contains("has no healthcheck"))
+            }
+            other => panic!("expected invalid configuration error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn dependency_cycle() {
+        let input = r#"
+            images {
+                image "api"
+                image "protobuf"
+            }
+            dependencies {
+                api {
+                    protobuf {
+                        api
+                    }
+                }
+            }
+        "#;
+
+        match parse("cycle.kdl", input) {
+            Err(IkkiConfigError::DependencyCycle(err)) => {
+                let rotations = [
+                    vec!["api", "protobuf", "api"],
+                    vec!["protobuf", "api", "protobuf"],
+                ];
+                assert!(rotations.contains(&err.cycle.iter().map(String::as_str).collect()));
+            }
+            other => panic!("expected dependency cycle error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn unknown_image_in_dependencies() {
+        let input = r#"
+            images {
+                image "api"
+                image "protobuf"
+            }
+            dependencies {
+                api {
+                    protbuf
+                    redis
+                }
+            }
+        "#;
+
+        match parse("unknown.kdl", input) {
+            Err(IkkiConfigError::UnknownImage(err)) => {
+                assert_eq!(err.names, vec!["protbuf", "redis"]);
+
+                let labels: Vec<String> = miette::Diagnostic::labels(&err)
+                    .unwrap()
+                    .filter_map(|label| label.label().map(String::from))
+                    .collect();
+                assert!(labels[0].contains("did you mean `protobuf`?"));
+                assert!(!labels[1].contains("did you mean"));
+            }
+            other => panic!("expected unknown image error, got {:?}", other),
+        }
+    }
 }
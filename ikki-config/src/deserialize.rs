@@ -1,6 +1,8 @@
 #![allow(dead_code)]
 
-use std::path::PathBuf;
+use std::{fmt, path::PathBuf, str::FromStr};
+
+use knuffel::span::Span;
 
 #[derive(Debug, Clone, knuffel::Decode)]
 pub struct KeyValue(
@@ -16,17 +18,118 @@ pub struct Secret {
     pub src: PathBuf,
 }
 
+/// Kind of a service mount, same as the `type` of Docker's `--mount`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MountType {
+    Bind,
+    Volume,
+    Tmpfs,
+}
+
+impl FromStr for MountType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bind" => Ok(MountType::Bind),
+            "volume" => Ok(MountType::Volume),
+            "tmpfs" => Ok(MountType::Tmpfs),
+            other => Err(format!(
+                "unknown mount type `{}`, expected `bind`, `volume` or `tmpfs`",
+                other
+            )),
+        }
+    }
+}
+
+impl fmt::Display for MountType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            MountType::Bind => "bind",
+            MountType::Volume => "volume",
+            MountType::Tmpfs => "tmpfs",
+        };
+        f.write_str(name)
+    }
+}
+
 #[derive(Debug, Clone, knuffel::Decode)]
+#[knuffel(span_type=Span)]
 pub struct Mount {
-    #[knuffel(property(name = "type"))]
-    pub mount_type: String,
+    #[knuffel(property(name = "type"), str)]
+    pub mount_type: MountType,
+    /// Volume name or host path (relative to the configuration file), absent for `tmpfs`
     #[knuffel(property)]
-    pub src: PathBuf,
+    pub src: Option<PathBuf>,
     #[knuffel(property)]
     pub dest: PathBuf,
+    #[knuffel(property, default)]
+    pub readonly: bool,
+    #[knuffel(span)]
+    pub span: Span,
 }
 
+/// What happens when a service container exits, same syntax as Docker's `--restart`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RestartPolicy {
+    #[default]
+    No,
+    /// Restart after a non-zero exit, at most the given number of consecutive times
+    OnFailure(Option<u32>),
+    Always,
+}
+
+impl FromStr for RestartPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "no" => Ok(RestartPolicy::No),
+            None if s == "always" => Ok(RestartPolicy::Always),
+            None if s == "on-failure" => Ok(RestartPolicy::OnFailure(None)),
+            Some(("on-failure", count)) => count
+                .parse()
+                .map(|count| RestartPolicy::OnFailure(Some(count)))
+                .map_err(|_| format!("invalid restart count `{}`", count)),
+            _ => Err(format!(
+                "unknown restart policy `{}`, expected `no`, `on-failure[:N]` or `always`",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for RestartPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RestartPolicy::No => f.write_str("no"),
+            RestartPolicy::OnFailure(None) => f.write_str("on-failure"),
+            RestartPolicy::OnFailure(Some(count)) => write!(f, "on-failure:{}", count),
+            RestartPolicy::Always => f.write_str("always"),
+        }
+    }
+}
+
+/// Command Docker runs inside a service container to check its health, durations are in seconds
 #[derive(Debug, Clone, knuffel::Decode)]
+pub struct Healthcheck {
+    /// A single argument runs in the container's shell, several are executed directly
+    #[knuffel(child, unwrap(arguments))]
+    pub test: Vec<String>,
+    #[knuffel(child, unwrap(argument))]
+    pub interval: Option<u32>,
+    #[knuffel(child, unwrap(argument))]
+    pub timeout: Option<u32>,
+    /// Consecutive failures needed to consider the container unhealthy
+    #[knuffel(child, unwrap(argument))]
+    pub retries: Option<u32>,
+    /// Time to bootstrap, during which failures do not count towards `retries`
+    #[knuffel(child, unwrap(argument))]
+    pub start_period: Option<u32>,
+}
+
+#[derive(Debug, Clone, knuffel::Decode)]
+#[knuffel(span_type=Span)]
 pub struct Service {
     #[knuffel(child, unwrap(arguments))]
     pub ports: Option<Vec<String>>,
@@ -38,6 +141,19 @@ pub struct Service {
     pub mounts: Vec<Mount>,
     #[knuffel(child, unwrap(arguments))]
     pub networks: Option<Vec<String>>,
+    /// Seconds to wait for the container to stop before it is killed
+    #[knuffel(child, unwrap(argument))]
+    pub stop_timeout: Option<u32>,
+    /// Signal that stops the container, `SIGTERM` by default
+    #[knuffel(child, unwrap(argument))]
+    pub stop_signal: Option<String>,
+    #[knuffel(child)]
+    pub healthcheck: Option<Healthcheck>,
+    /// Applied by Ikki in `--watch` mode
+    #[knuffel(child, unwrap(argument, str), default)]
+    pub restart: RestartPolicy,
+    #[knuffel(span)]
+    pub span: Span,
 }
 
 #[derive(Debug, knuffel::Decode)]
@@ -47,6 +163,7 @@ pub struct BuildArg {
 }
 
 #[derive(Debug, Clone, knuffel::Decode)]
+#[knuffel(span_type=Span)]
 pub struct Image {
     #[knuffel(property)]
     pub path: Option<PathBuf>,
@@ -54,6 +171,9 @@ pub struct Image {
     pub file: Option<PathBuf>,
     #[knuffel(property)]
     pub output: Option<PathBuf>,
+    /// Path inside the image to export to `output`, the image working directory by default
+    #[knuffel(property(name = "output-src"))]
+    pub output_src: Option<PathBuf>,
     #[knuffel(property)]
     pub pull: Option<String>,
     #[knuffel(children(name = "build-arg"))]
@@ -67,15 +187,147 @@ pub struct Image {
 }
 
 #[derive(Debug, knuffel::Decode)]
+#[knuffel(span_type=Span)]
 pub struct Images {
     #[knuffel(children(name = "image"))]
     pub images: Vec<Image>,
 }
 
+/// A named volume shared by services
+#[derive(Debug, Clone, knuffel::Decode)]
+pub struct Volume {
+    #[knuffel(argument)]
+    pub name: String,
+    #[knuffel(property)]
+    pub driver: Option<String>,
+    /// Volume that already exists and is not created or removed by Ikki
+    #[knuffel(property, default)]
+    pub external: bool,
+    #[knuffel(children(name = "label"))]
+    pub labels: Vec<KeyValue>,
+    #[knuffel(children(name = "option"))]
+    pub options: Vec<KeyValue>,
+}
+
+#[derive(Debug, Default, knuffel::Decode)]
+pub struct Volumes {
+    #[knuffel(children(name = "volume"))]
+    pub volumes: Vec<Volume>,
+}
+
+/// A user-defined network shared by services
+#[derive(Debug, Clone, knuffel::Decode)]
+pub struct Network {
+    #[knuffel(argument)]
+    pub name: String,
+    #[knuffel(property)]
+    pub driver: Option<String>,
+    /// Network without access to the outside world
+    #[knuffel(property, default)]
+    pub internal: bool,
+    /// Network that already exists and is not created or removed by Ikki
+    #[knuffel(property, default)]
+    pub external: bool,
+    #[knuffel(children(name = "label"))]
+    pub labels: Vec<KeyValue>,
+    #[knuffel(children(name = "option"))]
+    pub options: Vec<KeyValue>,
+}
+
+#[derive(Debug, Default, knuffel::Decode)]
+pub struct Networks {
+    #[knuffel(children(name = "network"))]
+    pub networks: Vec<Network>,
+}
+
+/// State a service must reach before the services depending on it are started
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum DependencyCondition {
+    #[default]
+    Started,
+    Healthy,
+    CompletedSuccessfully,
+}
+
+impl FromStr for DependencyCondition {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "started" => Ok(DependencyCondition::Started),
+            "healthy" => Ok(DependencyCondition::Healthy),
+            "completed-successfully" => Ok(DependencyCondition::CompletedSuccessfully),
+            other => Err(format!(
+                "unknown condition `{}`, expected `started`, `healthy` or `completed-successfully`",
+                other
+            )),
+        }
+    }
+}
+
+impl fmt::Display for DependencyCondition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            DependencyCondition::Started => "started",
+            DependencyCondition::Healthy => "healthy",
+            DependencyCondition::CompletedSuccessfully => "completed-successfully",
+        };
+        f.write_str(name)
+    }
+}
+
+/// An image in the `dependencies` tree along with images it depends on
+#[derive(Debug, Clone, knuffel::Decode)]
+#[knuffel(span_type=Span)]
+pub struct Dependency {
+    #[knuffel(node_name)]
+    pub name: String,
+    /// State the depender waits for before it is started
+    #[knuffel(property, default, str)]
+    pub condition: DependencyCondition,
+    #[knuffel(children)]
+    pub dependencies: Vec<Dependency>,
+    #[knuffel(span)]
+    pub span: Span,
+}
+
+#[derive(Debug, knuffel::Decode)]
+#[knuffel(span_type=Span)]
+pub struct Dependencies {
+    #[knuffel(children)]
+    pub dependers: Vec<Dependency>,
+}
+
+/// Project settings, the project name defaults to the configuration directory name
+#[derive(Debug, Default, knuffel::Decode)]
+pub struct Project {
+    #[knuffel(argument)]
+    pub name: Option<String>,
+    /// Prefix tags of built images with the project name
+    #[knuffel(property(name = "prefix-images"), default)]
+    pub prefix_images: bool,
+}
+
 #[derive(Debug, knuffel::Decode)]
+#[knuffel(span_type=Span)]
 pub struct ImageConfig {
+    #[knuffel(child, default)]
+    pub project: Project,
     #[knuffel(child)]
     pub images: Images,
+    /// Dependencies needed both to build and to run images
+    #[knuffel(child)]
+    pub dependencies: Option<Dependencies>,
+    /// Dependencies needed only to build images
+    #[knuffel(child)]
+    pub build_deps: Option<Dependencies>,
+    /// Dependencies needed only to run services
+    #[knuffel(child)]
+    pub run_deps: Option<Dependencies>,
+    #[knuffel(child, default)]
+    pub volumes: Volumes,
+    #[knuffel(child, default)]
+    pub networks: Networks,
 }
 
 impl ImageConfig {
@@ -86,8 +338,45 @@ impl ImageConfig {
             .map(|img| img.name.clone())
             .collect()
     }
+
+    /// Sections whose edges order image builds: `dependencies` and `build-deps`
+    pub fn build_dependencies(&self) -> Vec<&Dependencies> {
+        self.dependencies.iter().chain(&self.build_deps).collect()
+    }
+
+    /// Sections whose edges order service starts: `dependencies` and `run-deps`
+    pub fn run_dependencies(&self) -> Vec<&Dependencies> {
+        self.dependencies.iter().chain(&self.run_deps).collect()
+    }
 }
 
 pub fn parse_image_config(filename: &str, input: &str) -> Result<ImageConfig, knuffel::Error> {
     knuffel::parse::<ImageConfig>(filename, input)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn restart_policies() {
+        assert_eq!("no".parse(), Ok(RestartPolicy::No));
+        assert_eq!("always".parse(), Ok(RestartPolicy::Always));
+        assert_eq!("on-failure".parse(), Ok(RestartPolicy::OnFailure(None)));
+        assert_eq!(
+            "on-failure:3".parse(),
+            Ok(RestartPolicy::OnFailure(Some(3)))
+        );
+    }
+
+    #[test]
+    fn invalid_restart_policies() {
+        let err = "unless-stopped".parse::<RestartPolicy>().unwrap_err();
+        assert!(err.contains("unknown restart policy `unless-stopped`"));
+
+        let err = "on-failure:many".parse::<RestartPolicy>().unwrap_err();
+        assert!(err.contains("invalid restart count `many`"));
+
+        assert!("always:3".parse::<RestartPolicy>().is_err());
+    }
+}
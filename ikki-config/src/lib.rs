@@ -5,5 +5,10 @@ mod parse;
 pub use deserialize::*;
 pub use parse::parse;
 pub use parse::BuildOrder;
+pub use parse::DependencyCycleError;
+pub use parse::DependencyGraph;
 pub use parse::IkkiConfig;
 pub use parse::IkkiConfigError;
+pub use parse::UndeclaredResourceError;
+pub use parse::UnknownImageError;
+pub use parse::EXCLUSIVE_NETWORKS;
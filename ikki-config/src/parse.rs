@@ -1,23 +1,423 @@
-use crate::{deps::parse_deps, parse_image_config, Image, ImageConfig};
-use kdl::{KdlDocument, KdlError};
-use toposort::Toposort;
+use std::collections::HashSet;
+use std::fmt::Display;
+use std::path::{Component, Path, PathBuf};
+
+use crate::{
+    deps::{add_deps, cycle_labels, edges, name_span, unknown_images},
+    parse_image_config, Dependencies, Dependency, DependencyCondition, Image, ImageConfig,
+    MountType, Network, Volume,
+};
+use knuffel::span::Span;
+use miette::{Diagnostic, LabeledSpan, NamedSource, SourceCode, SourceSpan};
+use toposort::{Dag, Toposort};
 
 use thiserror::Error;
 
 pub type BuildOrder = Vec<Vec<String>>;
+pub type DependencyGraph = Dag<String>;
 
-#[derive(Error, Debug)]
+#[derive(Error, Debug, Diagnostic)]
 pub enum IkkiConfigError {
     #[error("Invalid Ikki configuration: {0}")]
     InvalidConfiguration(String),
     #[error("Configuration deserialization failed: {0}")]
+    #[diagnostic(transparent)]
     Knuffel(#[from] knuffel::Error),
+    #[error(transparent)]
+    #[diagnostic(transparent)]
+    DependencyCycle(#[from] DependencyCycleError),
+    #[error(transparent)]
+    #[diagnostic(transparent)]
+    UnknownImage(#[from] UnknownImageError),
+    #[error(transparent)]
+    #[diagnostic(transparent)]
+    UndeclaredResource(#[from] UndeclaredResourceError),
+}
+
+#[derive(Error, Debug)]
+#[error("Dependency cycle detected: {}", cycle.join(" -> "))]
+pub struct DependencyCycleError {
+    /// Images in the cycle, each one depending on the next
+    pub cycle: Vec<String>,
+    src: NamedSource,
+    labels: Vec<LabeledSpan>,
+}
+
+impl Diagnostic for DependencyCycleError {
+    fn code<'a>(&'a self) -> Option<Box<dyn Display + 'a>> {
+        Some(Box::new("ikki::dependency_cycle"))
+    }
+
+    fn help<'a>(&'a self) -> Option<Box<dyn Display + 'a>> {
+        Some(Box::new(
+            "remove one of the labeled dependencies to break the cycle",
+        ))
+    }
+
+    fn source_code(&self) -> Option<&dyn SourceCode> {
+        Some(&self.src)
+    }
+
+    fn labels(&self) -> Option<Box<dyn Iterator<Item = LabeledSpan> + '_>> {
+        Some(Box::new(self.labels.iter().cloned()))
+    }
+}
+
+#[derive(Error, Debug)]
+#[error("Unknown image(s) in dependencies: {}", names.join(", "))]
+pub struct UnknownImageError {
+    /// Names used in dependency sections that are not declared in `images`
+    pub names: Vec<String>,
+    src: NamedSource,
+    labels: Vec<LabeledSpan>,
+}
+
+impl Diagnostic for UnknownImageError {
+    fn code<'a>(&'a self) -> Option<Box<dyn Display + 'a>> {
+        Some(Box::new("ikki::unknown_image"))
+    }
+
+    fn help<'a>(&'a self) -> Option<Box<dyn Display + 'a>> {
+        Some(Box::new(
+            "every name in `dependencies`, `build-deps` and `run-deps` must match an image declared in `images`",
+        ))
+    }
+
+    fn source_code(&self) -> Option<&dyn SourceCode> {
+        Some(&self.src)
+    }
+
+    fn labels(&self) -> Option<Box<dyn Iterator<Item = LabeledSpan> + '_>> {
+        Some(Box::new(self.labels.iter().cloned()))
+    }
+}
+
+#[derive(Error, Debug)]
+#[error("Undeclared volume(s) or network(s) used by services: {}", names.join(", "))]
+pub struct UndeclaredResourceError {
+    /// Volumes and networks used by services that are not declared at the top level
+    pub names: Vec<String>,
+    src: NamedSource,
+    labels: Vec<LabeledSpan>,
+}
+
+impl Diagnostic for UndeclaredResourceError {
+    fn code<'a>(&'a self) -> Option<Box<dyn Display + 'a>> {
+        Some(Box::new("ikki::undeclared_resource"))
+    }
+
+    fn help<'a>(&'a self) -> Option<Box<dyn Display + 'a>> {
+        Some(Box::new(
+            "declare every volume under `volumes` and every network under `networks`",
+        ))
+    }
+
+    fn source_code(&self) -> Option<&dyn SourceCode> {
+        Some(&self.src)
+    }
+
+    fn labels(&self) -> Option<Box<dyn Iterator<Item = LabeledSpan> + '_>> {
+        Some(Box::new(self.labels.iter().cloned()))
+    }
+}
+
+/// Networks that exist on every Docker host and need no declaration
+const PREDEFINED_NETWORKS: [&str; 3] = ["bridge", "host", "none"];
+
+/// Network modes that cannot be combined with any other network
+pub const EXCLUSIVE_NETWORKS: [&str; 2] = ["host", "none"];
+
+/// The candidate most similar to `name`, if any is close enough.
+fn closest_name<'a>(name: &str, candidates: &'a [String]) -> Option<&'a str> {
+    candidates
+        .iter()
+        .map(|candidate| (candidate, strsim::jaro_winkler(name, candidate)))
+        .filter(|(_, similarity)| *similarity > 0.8)
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(candidate, _)| candidate.as_str())
+}
+
+fn validate_dependencies(
+    filename: &str,
+    input: &str,
+    image_config: &ImageConfig,
+) -> Result<(), UnknownImageError> {
+    let image_names = image_config.image_names();
+    let known: HashSet<String> = image_names.iter().cloned().collect();
+    let unknown: Vec<&Dependency> = [
+        &image_config.dependencies,
+        &image_config.build_deps,
+        &image_config.run_deps,
+    ]
+    .into_iter()
+    .flatten()
+    .flat_map(|deps| unknown_images(deps, &known))
+    .collect();
+
+    if unknown.is_empty() {
+        return Ok(());
+    }
+
+    let labels = unknown
+        .iter()
+        .map(|node| {
+            let label = match closest_name(&node.name, &image_names) {
+                Some(suggestion) => format!("unknown image, did you mean `{}`?", suggestion),
+                None => "unknown image".to_string(),
+            };
+            LabeledSpan::new_with_span(Some(label), name_span(input, node))
+        })
+        .collect();
+
+    let mut names: Vec<String> = vec![];
+    for node in unknown {
+        if !names.contains(&node.name) {
+            names.push(node.name.clone());
+        }
+    }
+
+    Err(UnknownImageError {
+        names,
+        src: NamedSource::new(filename, input.to_string()),
+        labels,
+    })
+}
+
+/// Conditions only apply to starting services, and `healthy` and `completed-successfully`
+/// need a service (with a healthcheck for `healthy`) to wait on.
+fn validate_conditions(image_config: &ImageConfig) -> Result<(), IkkiConfigError> {
+    if let Some(deps) = &image_config.build_deps {
+        if let Some((_, dependency)) = edges(deps)
+            .into_iter()
+            .find(|(_, dependency)| dependency.condition != DependencyCondition::Started)
+        {
+            return Err(IkkiConfigError::InvalidConfiguration(format!(
+                "condition on `{}` in `build-deps`, conditions only apply to starting services",
+                dependency.name
+            )));
+        }
+    }
+
+    for deps in image_config.run_dependencies() {
+        for (depender, dependency) in edges(deps) {
+            let service = image_config
+                .images
+                .images
+                .iter()
+                .find(|img| img.name == dependency.name)
+                .and_then(|img| img.service.as_ref());
+
+            let error = match (dependency.condition, service) {
+                (DependencyCondition::Started, _) => None,
+                (_, None) => Some("it has no service"),
+                (DependencyCondition::Healthy, Some(service)) if service.healthcheck.is_none() => {
+                    Some("its service has no healthcheck")
+                }
+                _ => None,
+            };
+
+            if let Some(error) = error {
+                return Err(IkkiConfigError::InvalidConfiguration(format!(
+                    "`{}` cannot wait for `{}` to be {}, because {}",
+                    depender.name, dependency.name, dependency.condition, error
+                )));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Span of the quoted `value` following `key` within `span`, or all of `span` if not found.
+fn value_span(input: &str, span: &Span, key: &str, value: &str) -> SourceSpan {
+    let text = &input[span.0..span.1];
+    let quoted = format!("\"{}\"", value);
+
+    text.find(key)
+        .and_then(|key_start| {
+            let offset = key_start + key.len();
+            text[offset..]
+                .find(&quoted)
+                .map(|start| span.0 + offset + start)
+        })
+        .map(|start| (start, quoted.len()).into())
+        .unwrap_or_else(|| (span.0, span.1 - span.0).into())
+}
+
+fn undeclared_label(kind: &str, name: &str, declared: &[String], span: SourceSpan) -> LabeledSpan {
+    let label = match closest_name(name, declared) {
+        Some(suggestion) => format!("undeclared {}, did you mean `{}`?", kind, suggestion),
+        None => format!("undeclared {}", kind),
+    };
+    LabeledSpan::new_with_span(Some(label), span)
+}
+
+fn validate_resources(
+    filename: &str,
+    input: &str,
+    image_config: &ImageConfig,
+) -> Result<(), IkkiConfigError> {
+    let volumes: Vec<String> = image_config
+        .volumes
+        .volumes
+        .iter()
+        .map(|volume| volume.name.clone())
+        .collect();
+    let networks: Vec<String> = image_config
+        .networks
+        .networks
+        .iter()
+        .map(|network| network.name.clone())
+        .collect();
+
+    let mut names: Vec<String> = vec![];
+    let mut labels = vec![];
+    let services = image_config
+        .images
+        .images
+        .iter()
+        .filter_map(|image| Some((&image.name, image.service.as_ref()?)));
+
+    for (image_name, service) in services {
+        let service_networks = service.networks.as_deref().unwrap_or_default();
+        let exclusive = service_networks
+            .iter()
+            .find(|name| EXCLUSIVE_NETWORKS.contains(&name.as_str()));
+        if let (Some(exclusive), true) = (exclusive, service_networks.len() > 1) {
+            return Err(IkkiConfigError::InvalidConfiguration(format!(
+                "service `{}` uses the `{}` network, which cannot be combined with other networks",
+                image_name, exclusive
+            )));
+        }
+
+        let volume_mounts = service
+            .mounts
+            .iter()
+            .filter(|mount| mount.mount_type == MountType::Volume);
+
+        for mount in volume_mounts {
+            let name = match &mount.src {
+                Some(src) => src.display().to_string(),
+                None => continue,
+            };
+            if !volumes.contains(&name) {
+                let span = value_span(input, &mount.span, "src", &name);
+                labels.push(undeclared_label("volume", &name, &volumes, span));
+                if !names.contains(&name) {
+                    names.push(name);
+                }
+            }
+        }
+
+        for name in service_networks {
+            if !networks.contains(name) && !PREDEFINED_NETWORKS.contains(&name.as_str()) {
+                let span = value_span(input, &service.span, "networks", name);
+                labels.push(undeclared_label("network", name, &networks, span));
+                if !names.contains(name) {
+                    names.push(name.clone());
+                }
+            }
+        }
+    }
+
+    if labels.is_empty() {
+        return Ok(());
+    }
+
+    Err(UndeclaredResourceError {
+        names,
+        src: NamedSource::new(filename, input.to_string()),
+        labels,
+    }
+    .into())
+}
+
+fn validate_mounts(image_config: &ImageConfig) -> Result<(), IkkiConfigError> {
+    for image in &image_config.images.images {
+        let mounts = image.service.iter().flat_map(|service| &service.mounts);
+
+        for mount in mounts {
+            let problem = match (mount.mount_type, &mount.src) {
+                (MountType::Tmpfs, Some(_)) => "does not take a `src`",
+                (MountType::Bind | MountType::Volume, None) => "requires a `src`",
+                _ => continue,
+            };
+
+            return Err(IkkiConfigError::InvalidConfiguration(format!(
+                "{} mount at `{}` of image `{}` {}",
+                mount.mount_type,
+                mount.dest.display(),
+                image.name,
+                problem
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+fn validate_healthchecks(image_config: &ImageConfig) -> Result<(), IkkiConfigError> {
+    let healthchecks = image_config.images.images.iter().filter_map(|image| {
+        let service = image.service.as_ref()?;
+        Some((&image.name, service.healthcheck.as_ref()?))
+    });
+
+    for (image_name, healthcheck) in healthchecks {
+        if healthcheck.test.is_empty() {
+            return Err(IkkiConfigError::InvalidConfiguration(format!(
+                "healthcheck of image `{}` requires a `test` command",
+                image_name
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// Drops `.` components, which Docker does not accept in bind sources.
+fn without_cur_dir(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|component| *component != Component::CurDir)
+        .collect()
+}
+
+/// Absolute directory of the configuration file, which relative bind sources are resolved against.
+/// It is canonicalized so that a path such as `../ikki.kdl` still ends with the directory name.
+fn config_dir(filename: &str) -> PathBuf {
+    let dir = Path::new(filename)
+        .parent()
+        .unwrap_or_else(|| Path::new(""));
+    let dir = match std::env::current_dir() {
+        Ok(cwd) => cwd.join(dir),
+        Err(_) => dir.to_path_buf(),
+    };
+    dir.canonicalize().unwrap_or_else(|_| without_cur_dir(&dir))
+}
+
+fn resolve_bind_sources(image_config: &mut ImageConfig, dir: &Path) {
+    let mounts = image_config
+        .images
+        .images
+        .iter_mut()
+        .flat_map(|image| image.service.iter_mut())
+        .flat_map(|service| service.mounts.iter_mut())
+        .filter(|mount| mount.mount_type == MountType::Bind);
+
+    for mount in mounts {
+        if let Some(src) = &mut mount.src {
+            *src = without_cur_dir(&dir.join(&src));
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct IkkiConfig {
     image_config: ImageConfig,
     build_order: Vec<Vec<String>>,
+    dag: DependencyGraph,
+    start_order: Vec<Vec<String>>,
+    run_dag: DependencyGraph,
+    root: PathBuf,
 }
 
 impl IkkiConfig {
@@ -25,6 +425,16 @@ impl IkkiConfig {
         &self.image_config.images.images
     }
 
+    /// Volumes declared under the top-level `volumes` node
+    pub fn volumes(&self) -> &[Volume] {
+        &self.image_config.volumes.volumes
+    }
+
+    /// Networks declared under the top-level `networks` node
+    pub fn networks(&self) -> &[Network] {
+        &self.image_config.networks.networks
+    }
+
     pub fn find_image(&self, name: &str) -> Option<&Image> {
         self.image_config
             .images
@@ -33,36 +443,521 @@ impl IkkiConfig {
             .find(|img| img.name == name)
     }
 
+    /// Directory containing the configuration file
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Name of the project: the one set with `set_project_name`, the one in the
+    /// configuration, or the name of the directory containing the configuration file.
+    pub fn project_name(&self) -> String {
+        let name = match &self.image_config.project.name {
+            Some(name) => name.clone(),
+            None => self
+                .root
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default(),
+        };
+
+        normalize_project_name(&name)
+    }
+
+    pub fn set_project_name(&mut self, name: String) {
+        self.image_config.project.name = Some(name);
+    }
+
+    /// Docker name of a volume used by services. Declared volumes are prefixed with the project
+    /// name, as Docker Compose does, while external ones keep the name they already exist under.
+    pub fn volume_name(&self, name: &str) -> String {
+        let scoped = self
+            .volumes()
+            .iter()
+            .any(|vol| vol.name == name && !vol.external);
+        self.scoped_name(name, scoped)
+    }
+
+    /// Docker name of a network used by services, prefixed like `volume_name`. Predefined
+    /// networks such as `host` are never declared, so they keep their name.
+    pub fn network_name(&self, name: &str) -> String {
+        let scoped = self
+            .networks()
+            .iter()
+            .any(|net| net.name == name && !net.external);
+        self.scoped_name(name, scoped)
+    }
+
+    fn scoped_name(&self, name: &str, scoped: bool) -> String {
+        if scoped {
+            format!("{}_{}", self.project_name(), name)
+        } else {
+            name.to_string()
+        }
+    }
+
+    /// Tag of a built image, prefixed with the project name if `prefix-images` is set
+    pub fn image_tag(&self, name: &str) -> String {
+        if self.image_config.project.prefix_images {
+            format!("{}-{}", self.project_name(), name)
+        } else {
+            name.to_string()
+        }
+    }
+
     pub fn build_order(&self) -> BuildOrder {
         self.build_order.clone()
     }
+
+    /// Graph of all images where every image comes before its dependers.
+    pub fn dependency_graph(&self) -> DependencyGraph {
+        self.dag.clone()
+    }
+
+    /// Graph restricted to `name` and everything it transitively depends on.
+    pub fn dependencies_graph(&self, name: &str) -> DependencyGraph {
+        let mut images = self.dag.ancestors(&name.to_string());
+        images.push(name.to_string());
+        self.dag.induced_subgraph(&images)
+    }
+
+    /// Graph restricted to `name` and everything that transitively depends on it.
+    pub fn dependers_graph(&self, name: &str) -> DependencyGraph {
+        let mut images = self.dag.descendants(&name.to_string());
+        images.push(name.to_string());
+        self.dag.induced_subgraph(&images)
+    }
+
+    /// Graph restricted to `name` and its transitive dependers that are also `target` or
+    /// dependencies of it, so that changes are only rebuilt as far as `target` needs them.
+    pub fn dependers_graph_within(&self, name: &str, target: &str) -> DependencyGraph {
+        let mut scope = self.dag.ancestors(&target.to_string());
+        scope.push(target.to_string());
+
+        let mut images: Vec<String> = self
+            .dag
+            .descendants(&name.to_string())
+            .into_iter()
+            .filter(|image| scope.contains(image))
+            .collect();
+        images.push(name.to_string());
+        self.dag.induced_subgraph(&images)
+    }
+
+    /// Build order restricted to `name` and everything it transitively depends on.
+    pub fn dependencies_of(&self, name: &str) -> BuildOrder {
+        subgraph_order(self.dependencies_graph(name))
+    }
+
+    /// Build order restricted to `name` and everything that transitively depends on it.
+    pub fn dependers_of(&self, name: &str) -> BuildOrder {
+        subgraph_order(self.dependers_graph(name))
+    }
+
+    /// Order in which service containers are started, following `dependencies` and `run-deps`.
+    pub fn start_order(&self) -> BuildOrder {
+        self.start_order.clone()
+    }
+
+    /// Images `name` directly depends on at runtime, with the condition each one must
+    /// meet before `name` is started.
+    pub fn start_conditions(&self, name: &str) -> Vec<(String, DependencyCondition)> {
+        let mut conditions = vec![];
+        for deps in self.image_config.run_dependencies() {
+            for (depender, dependency) in edges(deps) {
+                let condition = (dependency.name.clone(), dependency.condition);
+                if depender.name == name && !conditions.contains(&condition) {
+                    conditions.push(condition);
+                }
+            }
+        }
+        conditions
+    }
+
+    /// Start order of the images rebuilt after a change to `name` and of everything
+    /// that transitively depends on those at runtime.
+    pub fn restart_order(&self, name: &str) -> BuildOrder {
+        let mut images = self.dag.descendants(&name.to_string());
+        images.push(name.to_string());
+
+        for image in images.clone() {
+            for depender in self.run_dag.descendants(&image) {
+                if !images.contains(&depender) {
+                    images.push(depender);
+                }
+            }
+        }
+
+        subgraph_order(self.run_dag.induced_subgraph(&images))
+    }
+}
+
+/// Lowercases the name and drops characters Docker does not allow in names and tags.
+fn normalize_project_name(name: &str) -> String {
+    let name: String = name
+        .to_lowercase()
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '_')
+        .collect();
+    let name = name.trim_start_matches(['-', '_']);
+
+    if name.is_empty() {
+        "ikki".to_string()
+    } else {
+        name.to_string()
+    }
+}
+
+fn subgraph_order(graph: DependencyGraph) -> BuildOrder {
+    graph
+        .toposort()
+        .expect("subgraph of an acyclic graph must be acyclic")
+}
+
+/// Graph of `images` with the edges of every section in `sections`, along with its order.
+fn dependency_order(
+    filename: &str,
+    input: &str,
+    images: &[String],
+    sections: &[&Dependencies],
+) -> Result<(DependencyGraph, BuildOrder), DependencyCycleError> {
+    // Register every image up front so that images absent from the dependency
+    // sections still land in the first chunk, in declaration order.
+    let mut dag = Dag::new();
+    for name in images {
+        dag.add_node(name.clone());
+    }
+    for deps in sections {
+        add_deps(&mut dag, deps);
+    }
+
+    let order = dag.toposort().map_err(|err| {
+        // The DAG orders dependencies before dependers, so reverse the path
+        // to read it as "depends on".
+        let cycle: Vec<String> = err.path.into_iter().rev().collect();
+        DependencyCycleError {
+            labels: sections
+                .iter()
+                .flat_map(|deps| cycle_labels(input, deps, &cycle))
+                .collect(),
+            src: NamedSource::new(filename, input.to_string()),
+            cycle,
+        }
+    })?;
+
+    Ok((dag, order))
 }
 
 pub fn parse(filename: &str, input: &str) -> Result<IkkiConfig, IkkiConfigError> {
-    let doc: KdlDocument = input
-        .parse()
-        .map_err(|e: KdlError| IkkiConfigError::InvalidConfiguration(e.to_string()))?;
-
-    let images = doc
-        .get("images")
-        .ok_or(IkkiConfigError::InvalidConfiguration(
-            "missing `images` configuration".to_string(),
-        ))?;
-
-    let dependencies = doc.get("dependencies");
-    let image_config = parse_image_config(filename, &images.to_string())?;
-    let build_order = dependencies
-        .map(|deps| {
-            let dag = parse_deps(deps);
-            dag.toposort().unwrap_or_default()
-        })
-        .unwrap_or_else(|| {
-            let image_names = image_config.image_names();
-            vec![image_names]
-        });
+    let mut image_config = parse_image_config(filename, input)?;
+
+    validate_dependencies(filename, input, &image_config)?;
+    validate_conditions(&image_config)?;
+    validate_mounts(&image_config)?;
+    validate_healthchecks(&image_config)?;
+    validate_resources(filename, input, &image_config)?;
+    let root = config_dir(filename);
+    resolve_bind_sources(&mut image_config, &root);
+
+    let image_names = image_config.image_names();
+    let (dag, build_order) = dependency_order(
+        filename,
+        input,
+        &image_names,
+        &image_config.build_dependencies(),
+    )?;
+    let (run_dag, start_order) = dependency_order(
+        filename,
+        input,
+        &image_names,
+        &image_config.run_dependencies(),
+    )?;
 
     Ok(IkkiConfig {
         image_config,
         build_order,
+        dag,
+        start_order,
+        run_dag,
+        root,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bind_mount_without_src() {
+        let input = r#"
+            images {
+                image "api" {
+                    service {
+                        mount type="bind" dest="/config"
+                    }
+                }
+            }
+        "#;
+
+        match parse("mounts.kdl", input) {
+            Err(IkkiConfigError::InvalidConfiguration(err)) => {
+                assert!(err.contains("bind mount at `/config` of image `api` requires a `src`"))
+            }
+            other => panic!("expected invalid configuration, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn tmpfs_mount_with_src() {
+        let input = r#"
+            images {
+                image "api" {
+                    service {
+                        mount type="tmpfs" src="scratch" dest="/tmp"
+                    }
+                }
+            }
+        "#;
+
+        match parse("mounts.kdl", input) {
+            Err(IkkiConfigError::InvalidConfiguration(err)) => {
+                assert!(err.contains("tmpfs mount at `/tmp` of image `api` does not take a `src`"))
+            }
+            other => panic!("expected invalid configuration, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn volume_mount() {
+        let input = r#"
+            images {
+                image "api" {
+                    service {
+                        mount type="volume" src="cache" dest="/cache" readonly=true
+                    }
+                }
+            }
+            volumes {
+                volume "cache"
+            }
+        "#;
+
+        let config = parse("mounts.kdl", input).unwrap();
+        let service = config.find_image("api").unwrap().service.as_ref().unwrap();
+
+        assert_eq!(service.mounts.len(), 1);
+        assert_eq!(service.mounts[0].src, Some("cache".into()));
+        assert!(service.mounts[0].readonly);
+    }
+
+    #[test]
+    fn exclusive_network_with_others() {
+        let input = r#"
+            images {
+                image "api" {
+                    service {
+                        networks "backend" "host"
+                    }
+                }
+            }
+            networks {
+                network "backend"
+            }
+        "#;
+
+        match parse("networks.kdl", input) {
+            Err(IkkiConfigError::InvalidConfiguration(err)) => assert!(err.contains(
+                "service `api` uses the `host` network, which cannot be combined with other networks"
+            )),
+            other => panic!("expected invalid configuration, got {:?}", other),
+        }
+
+        let input = r#"
+            images {
+                image "api" {
+                    service {
+                        networks "none"
+                    }
+                }
+            }
+        "#;
+
+        assert!(parse("networks.kdl", input).is_ok());
+    }
+
+    #[test]
+    fn undeclared_volume() {
+        let input = r#"
+            images {
+                image "api" {
+                    service {
+                        mount type="volume" src="cahce" dest="/cache"
+                    }
+                }
+            }
+            volumes {
+                volume "cache"
+            }
+        "#;
+
+        match parse("volumes.kdl", input) {
+            Err(IkkiConfigError::UndeclaredResource(err)) => {
+                assert_eq!(err.names, vec!["cahce"]);
+
+                let labels: Vec<_> = miette::Diagnostic::labels(&err).unwrap().collect();
+                assert_eq!(labels.len(), 1);
+                assert_eq!(
+                    labels[0].label(),
+                    Some("undeclared volume, did you mean `cache`?")
+                );
+
+                let span = &labels[0];
+                assert_eq!(
+                    &input[span.offset()..span.offset() + span.len()],
+                    "\"cahce\""
+                );
+            }
+            other => panic!("expected undeclared resource error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn undeclared_network() {
+        let input = r#"
+            images {
+                image "api" {
+                    service {
+                        networks "backend" "bridge" "metrics"
+                    }
+                }
+            }
+            networks {
+                network "backend"
+            }
+        "#;
+
+        match parse("networks.kdl", input) {
+            Err(IkkiConfigError::UndeclaredResource(err)) => {
+                assert_eq!(err.names, vec!["metrics"]);
+
+                let labels: Vec<_> = miette::Diagnostic::labels(&err).unwrap().collect();
+                assert_eq!(labels.len(), 1);
+                assert_eq!(labels[0].label(), Some("undeclared network"));
+
+                let span = &labels[0];
+                assert_eq!(
+                    &input[span.offset()..span.offset() + span.len()],
+                    "\"metrics\""
+                );
+            }
+            other => panic!("expected undeclared resource error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn project_name_from_directory() {
+        let input = r#"
+            images {
+                image "api"
+            }
+        "#;
+        let config = parse("my-app/ikki.kdl", input).unwrap();
+
+        assert_eq!(config.project_name(), "my-app");
+    }
+
+    #[test]
+    fn project_name_from_parent_directory() {
+        let input = r#"
+            images {
+                image "api"
+            }
+        "#;
+        let config = parse("src/../fixtures/ikki.kdl", input).unwrap();
+
+        assert_eq!(config.project_name(), "fixtures");
+        assert!(config.root().ends_with("fixtures"));
+    }
+
+    #[test]
+    fn normalized_project_name() {
+        let input = r#"
+            project "app"
+            images {
+                image "api"
+            }
+        "#;
+        let mut config = parse("ikki.kdl", input).unwrap();
+        config.set_project_name("My Proj".to_string());
+
+        assert_eq!(config.project_name(), "myproj");
+    }
+
+    #[test]
+    fn prefixed_image_tags() {
+        let input = r#"
+            project "app" prefix-images=true
+            images {
+                image "api"
+            }
+        "#;
+        let config = parse("ikki.kdl", input).unwrap();
+
+        assert_eq!(config.image_tag("api"), "app-api");
+
+        let input = r#"
+            project "app"
+            images {
+                image "api"
+            }
+        "#;
+        let config = parse("ikki.kdl", input).unwrap();
+
+        assert_eq!(config.image_tag("api"), "api");
+    }
+
+    #[test]
+    fn scoped_resource_names() {
+        let input = r#"
+            project "app"
+            images {
+                image "api"
+            }
+            volumes {
+                volume "cache"
+                volume "shared" external=true
+            }
+            networks {
+                network "backend"
+            }
+        "#;
+        let config = parse("ikki.kdl", input).unwrap();
+
+        assert_eq!(config.volume_name("cache"), "app_cache");
+        assert_eq!(config.volume_name("shared"), "shared");
+        assert_eq!(config.network_name("backend"), "app_backend");
+        assert_eq!(config.network_name("host"), "host");
+    }
+
+    #[test]
+    fn healthcheck_without_test() {
+        let input = r#"
+            images {
+                image "db" {
+                    service {
+                        healthcheck {
+                            test
+                            interval 5
+                        }
+                    }
+                }
+            }
+        "#;
+
+        match parse("healthcheck.kdl", input) {
+            Err(IkkiConfigError::InvalidConfiguration(err)) => {
+                assert!(err.contains("healthcheck of image `db` requires a `test` command"))
+            }
+            other => panic!("expected invalid configuration, got {:?}", other),
+        }
+    }
+}
@@ -1,5 +1,7 @@
-use clap::{Args, Parser, Subcommand};
-use std::path::PathBuf;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use std::{num::NonZeroUsize, path::PathBuf};
+
+use crate::logs::parse_since;
 
 const DEFAULT_CONFIG_FILE: &str = "ikki.kdl";
 
@@ -14,14 +16,22 @@ pub struct Ikki {
     /// Path to Ikki configuration file
     #[clap(long, short, value_parser, default_value = DEFAULT_CONFIG_FILE)]
     pub file: PathBuf,
+    /// Project name, overriding the configured one
+    #[clap(long, short, value_parser)]
+    pub project_name: Option<String>,
 }
 
 /// Ikki subcommand
 #[derive(Debug, Subcommand)]
 pub enum Command {
+    /// Build (or pull) all images, or a single image and its dependencies
     Build(BuildCmdArgs),
     /// Build (or pull) all images and start the services
     Up(UpOptions),
+    /// Stop and remove the containers and networks of the project
+    Down(DownOptions),
+    /// Show the output of services
+    Logs(LogsCmdArgs),
     Explain,
 }
 
@@ -29,11 +39,72 @@ pub enum Command {
 pub struct UpOptions {
     #[clap(long)]
     /// Watch for FS changes and Docker events to trigger necessary rebuilds and restarts
-    watch: bool,
+    pub watch: bool,
+    #[clap(flatten)]
+    pub scheduling: SchedulingArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct DownOptions {
+    #[clap(long)]
+    /// Also remove the volumes of the project
+    pub volumes: bool,
+    #[clap(long, value_enum)]
+    /// Also remove images, only built ones with `local` or pulled ones as well with `all`
+    pub rmi: Option<RemoveImages>,
+}
+
+#[derive(Args, Debug)]
+pub struct LogsCmdArgs {
+    /// Services to show the output of [default: all]
+    #[clap(value_parser)]
+    pub services: Vec<String>,
+    #[clap(long, short)]
+    /// Keep streaming new output
+    pub follow: bool,
+    #[clap(long, value_parser)]
+    /// Number of lines to show from the end of the output of each service
+    pub tail: Option<usize>,
+    #[clap(long, value_parser = parse_since)]
+    /// Only show output since a Unix timestamp or a duration ago such as `10m`
+    pub since: Option<i64>,
+    #[clap(long, short)]
+    /// Show timestamps
+    pub timestamps: bool,
+    #[clap(long)]
+    /// Do not prefix lines with the service name
+    pub no_prefix: bool,
+}
+
+/// Images removed by `down --rmi`
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum RemoveImages {
+    Local,
+    All,
+}
+
+#[derive(Args, Debug)]
+pub struct SchedulingArgs {
+    #[clap(long, short, value_parser)]
+    /// Maximum number of images to build or pull at the same time [default: unlimited]
+    pub jobs: Option<NonZeroUsize>,
+    #[clap(long)]
+    /// Start images with the longest chain of dependers first
+    pub critical_path_first: bool,
+    #[clap(long)]
+    /// Keep building images that do not depend on failed ones and report all failures at the end
+    pub keep_going: bool,
 }
 
 #[derive(Debug, Args)]
 pub struct BuildCmdArgs {
+    /// Image to build along with everything it depends on
     #[clap(value_parser)]
-    name: Option<String>,
+    pub name: Option<String>,
+    #[clap(long)]
+    /// Watch for FS changes and rebuild changed images and their dependers, limited to
+    /// the image given by name and its dependencies
+    pub watch: bool,
+    #[clap(flatten)]
+    pub scheduling: SchedulingArgs,
 }
@@ -1,19 +1,23 @@
-use std::ops::Mul;
+use std::collections::HashMap;
 
 use bollard::Docker;
 use futures::prelude::*;
 use futures::stream::FuturesUnordered;
-use ikki_config::{BuildOrder, IkkiConfig, Image};
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use ikki_config::{BuildOrder, DependencyGraph, IkkiConfig, Image};
+use indicatif::MultiProgress;
 use tokio::sync::oneshot::Sender;
 use tokio::sync::{mpsc, oneshot};
 use tokio::task;
 use tracing::debug;
 
 use crate::docker::DockerError;
+use crate::docker_config::{
+    build_options, condition_timeout, container_name, create_run_options, default_network_name,
+    network_options, project_labels, volume_options, BuildOptions, NetworkOptions,
+};
 use crate::{docker, IkkiError};
 
-type ImageName = String;
+pub type ImageName = String;
 
 #[derive(Debug)]
 pub enum BuildResult {
@@ -37,45 +41,107 @@ type BuildResultSender = oneshot::Sender<BuildResult>;
 type RunResultSender = oneshot::Sender<RunResult>;
 type StopResultSender = oneshot::Sender<StopResult>;
 
-type ContainerIds = Vec<String>;
+/// Container IDs by the name of the image they run
+type ContainerIds = HashMap<ImageName, String>;
 
 #[derive(Debug)]
 pub enum Command {
-    Build((ImageName, BuildResultSender)),
+    /// Builds the image and its dependers, only the ones the second image depends on if given
+    Build((ImageName, Option<ImageName>, BuildResultSender)),
+    BuildWithDependencies((ImageName, BuildResultSender)),
     Run((ImageName, RunResultSender)),
+    Restart((ImageName, RunResultSender)),
     BuildAll(BuildResultSender),
-    RunAll(RunResultSender),
+    RunAll((Vec<ImageName>, RunResultSender)),
     StopAll((ContainerIds, StopResultSender)),
 }
 
 pub type CommandReceiver = mpsc::Receiver<Command>;
 pub type CommandSender = mpsc::Sender<Command>;
 
+/// How build jobs are scheduled
+#[derive(Debug, Clone, Default)]
+pub struct ScheduleOptions {
+    /// Maximum number of concurrent builds/pulls, unlimited if `None`
+    pub jobs: Option<usize>,
+    /// Start images with the longest chain of dependers first
+    pub critical_path_first: bool,
+    /// Keep building images that do not depend on failed ones
+    pub keep_going: bool,
+}
+
+/// Outcome of every image in a build that kept going after failures
+#[derive(Debug, Default)]
+pub struct BuildReport {
+    pub succeeded: Vec<ImageName>,
+    pub failed: Vec<(ImageName, DockerError)>,
+    /// Skipped images along with the failed image they depend on
+    pub skipped: Vec<(ImageName, ImageName)>,
+}
+
+impl BuildReport {
+    /// Images that are not available because they failed or were skipped
+    pub fn unavailable(&self) -> Vec<ImageName> {
+        self.failed
+            .iter()
+            .map(|(name, _)| name)
+            .chain(self.skipped.iter().map(|(name, _)| name))
+            .cloned()
+            .collect()
+    }
+}
+
 struct Builder {
     receiver: CommandReceiver,
     client: Docker,
     config: IkkiConfig,
+    options: ScheduleOptions,
+    project: String,
+    /// Project network that every service container joins
+    network: String,
 }
 
 async fn create_docker_job(
     docker: Docker,
     image: Image,
+    build_opts: Result<BuildOptions, DockerError>,
     mp: MultiProgress,
 ) -> Result<(), DockerError> {
     if let Some(_pull) = &image.pull {
         docker::pull_image(docker, image, mp).await?;
     } else if let Some(_path) = &image.path {
-        docker::build_image(docker, image, mp).await?;
+        let build_opts = build_opts?;
+        let tag = build_opts.tag.clone();
+        let alias = build_opts.alias.clone();
+        let output = build_opts.output.clone();
+        let output_src = build_opts.output_src.clone();
+        docker::build_image(docker.clone(), image, build_opts, mp).await?;
+        if let Some(alias) = alias {
+            docker::tag_image(&docker, &tag, &alias).await?;
+        }
+        if let Some(output) = output {
+            docker::export_output(docker, &tag, output_src, output).await?;
+        }
     }
     Ok(())
 }
 
 impl Builder {
-    fn new(receiver: CommandReceiver, client: Docker, config: IkkiConfig) -> Self {
+    fn new(
+        receiver: CommandReceiver,
+        client: Docker,
+        config: IkkiConfig,
+        options: ScheduleOptions,
+    ) -> Self {
+        let project = config.project_name();
+        let network = default_network_name(&project);
         Self {
             receiver,
             client,
             config,
+            options,
+            project,
+            network,
         }
     }
 
@@ -122,18 +188,26 @@ impl Builder {
                 let result = self.full_build().await;
                 self.report_build_result(sender, result)
             }
-            Command::RunAll(sender) => {
-                let result = self.full_run().await;
+            Command::RunAll((skip, sender)) => {
+                let result = self.full_run(&skip).await;
                 self.report_run_result(sender, result)
             }
-            Command::Build((image_name, sender)) => {
-                let result = self.build_dependers(&image_name).await;
+            Command::Build((image_name, target, sender)) => {
+                let result = self.build_dependers(&image_name, target.as_deref()).await;
+                self.report_build_result(sender, result)
+            }
+            Command::BuildWithDependencies((image_name, sender)) => {
+                let result = self.build_dependencies(&image_name).await;
                 self.report_build_result(sender, result)
             }
             Command::Run((image_name, sender)) => {
                 let result = self.run_dependers(&image_name).await;
                 self.report_run_result(sender, result)
             }
+            Command::Restart((image_name, sender)) => {
+                let result = self.ordered_run(vec![vec![image_name]], &[], &[]).await;
+                self.report_run_result(sender, result)
+            }
             Command::StopAll((ids, sender)) => {
                 let result = self.stop_all(ids).await;
                 self.report_stop_result(sender, result)
@@ -141,44 +215,115 @@ impl Builder {
         }
     }
 
-    async fn ordered_build(&self, order: BuildOrder) -> Result<(), IkkiError> {
-        debug!("executing build jobs in configured order");
+    async fn scheduled_build(&self, graph: DependencyGraph) -> Result<(), IkkiError> {
+        debug!("executing build jobs as soon as their dependencies are built");
         let mp = MultiProgress::new();
 
-        for chunk in order {
-            // Concurrently run builds/pulls in a single chunk because they do not depend on each other.
-            let queue = FuturesUnordered::new();
+        let priorities = if self.options.critical_path_first {
+            graph
+                .critical_path_lengths()
+                .expect("dependency graph must be acyclic")
+        } else {
+            HashMap::new()
+        };
+
+        let mut pending_dependencies: HashMap<&String, usize> = graph
+            .nodes()
+            .map(|name| (name, graph.predecessors(name).len()))
+            .collect();
+
+        let mut ready: Vec<&String> = graph
+            .nodes()
+            .filter(|name| pending_dependencies[name] == 0)
+            .collect();
+
+        let jobs = self.options.jobs.unwrap_or(usize::MAX);
+        let mut running = FuturesUnordered::new();
+        let mut report = BuildReport::default();
+
+        loop {
+            while running.len() < jobs && !ready.is_empty() {
+                // Pick the first ready image with the highest priority so that ties keep
+                // the graph order.
+                let next = ready
+                    .iter()
+                    .enumerate()
+                    .rev()
+                    .max_by_key(|(_, name)| priorities.get(**name).copied().unwrap_or(0))
+                    .map(|(position, _)| position)
+                    .expect("ready queue is not empty");
+                let image_name = ready.remove(next);
 
-            for image_name in chunk {
                 let image = self
                     .config
-                    .find_image(&image_name)
+                    .find_image(image_name)
                     .cloned()
-                    .ok_or(IkkiError::NoSuchImage(image_name))?;
-                let job = create_docker_job(self.client.clone(), image, mp.clone());
-                queue.push(job);
+                    .ok_or_else(|| IkkiError::NoSuchImage(image_name.clone()))?;
+                let build_opts = build_options(&self.config, &image);
+                let job = create_docker_job(self.client.clone(), image, build_opts, mp.clone())
+                    .map(move |result| (image_name, result));
+                running.push(job);
             }
 
-            queue
-                .collect::<Vec<_>>()
-                .await
-                .into_iter()
-                .collect::<Result<Vec<()>, DockerError>>()?;
+            match running.next().await {
+                Some((image_name, Ok(()))) => {
+                    report.succeeded.push(image_name.clone());
+                    for depender in graph.successors(image_name) {
+                        let pending = pending_dependencies
+                            .get_mut(depender)
+                            .expect("depender must be part of the graph");
+                        *pending -= 1;
+                        if *pending == 0 {
+                            ready.push(depender);
+                        }
+                    }
+                }
+                Some((image_name, Err(e))) if self.options.keep_going => {
+                    // Dependers can never become ready, so they are skipped.
+                    for depender in graph.descendants(image_name) {
+                        if !report.skipped.iter().any(|(name, _)| name == &depender) {
+                            report.skipped.push((depender, image_name.clone()));
+                        }
+                    }
+                    report.failed.push((image_name.clone(), e));
+                }
+                Some((image_name, Err(e))) => {
+                    return Err(IkkiError::ImageBuild(image_name.clone(), e));
+                }
+                None => break,
+            }
         }
 
         mp.clear().expect("failed to clear multiple progress bars");
 
+        if !report.failed.is_empty() {
+            return Err(IkkiError::BuildFailures(report));
+        }
+
         debug!("all build jobs finished successfully");
         Ok(())
     }
 
-    async fn ordered_run(&self, order: BuildOrder) -> Result<ContainerIds, IkkiError> {
+    async fn ordered_run(
+        &self,
+        order: BuildOrder,
+        skip: &[ImageName],
+        restart: &[ImageName],
+    ) -> Result<ContainerIds, IkkiError> {
         debug!("executing run jobs in configured order");
-        let mut container_ids = vec![];
+        let mut container_ids = ContainerIds::new();
+
+        self.create_resources().await?;
 
         for chunk in order {
             // Concurrently run builds/pulls in a single chunk because they do not depend on each other.
             let queue = FuturesUnordered::new();
+            let chunk: Vec<ImageName> = chunk
+                .into_iter()
+                .filter(|name| !skip.contains(name))
+                .collect();
+
+            self.wait_for_conditions(&chunk, skip).await?;
 
             for image_name in chunk {
                 let image = self
@@ -190,10 +335,15 @@ impl Builder {
                     let image_name = if let Some(name) = image.pull {
                         name
                     } else {
-                        image.name.clone()
+                        self.config.image_tag(&image.name)
                     };
-                    let container_name = image.name;
-                    let job = docker::run(self.client.clone(), container_name, image_name, service);
+                    let options = create_run_options((image.name.clone(), image_name, service))
+                        .with_resource_names(&self.config)
+                        .with_default_network(self.network.clone())
+                        .with_project(&self.project);
+                    let restart = restart.contains(&image.name);
+                    let job = docker::run(self.client.clone(), options, restart)
+                        .map_ok(move |id| (image.name, id));
                     queue.push(job);
                 }
             }
@@ -202,51 +352,149 @@ impl Builder {
                 .collect::<Vec<_>>()
                 .await
                 .into_iter()
-                .collect::<Result<Vec<String>, DockerError>>()?;
+                .collect::<Result<Vec<(ImageName, String)>, DockerError>>()?;
 
-            container_ids.extend_from_slice(&ids);
+            container_ids.extend(ids);
         }
         debug!("all run jobs finished successfully");
         Ok(container_ids)
     }
 
-    fn dependent_images_of(&self, name: &str) -> Vec<Vec<String>> {
-        let mut dependent_images: Vec<Vec<String>> = self
-            .config
-            .build_order()
-            .into_iter()
-            .skip_while(|chunk| !chunk.contains(&name.to_string()))
-            .collect();
+    /// Waits until the runtime dependencies of the services in `images` meet their conditions.
+    /// Dependencies in `skip` were never started, so they are not waited for.
+    async fn wait_for_conditions(
+        &self,
+        images: &[ImageName],
+        skip: &[ImageName],
+    ) -> Result<(), DockerError> {
+        let mut conditions = vec![];
+        for image in images {
+            let is_service = self
+                .config
+                .find_image(image)
+                .is_some_and(|img| img.service.is_some());
+            if !is_service {
+                continue;
+            }
+            for condition in self.config.start_conditions(image) {
+                if skip.contains(&condition.0) {
+                    println!(
+                        "Not waiting for {} to be {}, it is not available",
+                        condition.0, condition.1
+                    );
+                    continue;
+                }
+                if !conditions.contains(&condition) {
+                    conditions.push(condition);
+                }
+            }
+        }
+
+        let waits = conditions.into_iter().map(|(dependency, condition)| {
+            let healthcheck = self
+                .config
+                .find_image(&dependency)
+                .and_then(|img| img.service.as_ref())
+                .and_then(|service| service.healthcheck.as_ref());
+            let timeout = condition_timeout(condition, healthcheck);
+            let name = container_name(&self.project, &dependency);
+            docker::wait_for_condition(self.client.clone(), name, condition, timeout)
+        });
+        future::try_join_all(waits).await?;
 
-        // We want to avoid building anything in the same chunk as the target image
-        // but still need to build it
-        let chunk = &mut dependent_images[0];
-        *chunk = vec![name.to_string()];
+        Ok(())
+    }
 
-        dependent_images
+    async fn build_dependers(&self, name: &str, target: Option<&str>) -> Result<(), IkkiError> {
+        let dependers = match target {
+            Some(target) => self.config.dependers_graph_within(name, target),
+            None => self.config.dependers_graph(name),
+        };
+        self.scheduled_build(dependers).await
     }
 
-    async fn build_dependers(&self, name: &str) -> Result<(), IkkiError> {
-        let dependers = self.dependent_images_of(name);
-        self.ordered_build(dependers).await
+    async fn build_dependencies(&self, name: &str) -> Result<(), IkkiError> {
+        if self.config.find_image(name).is_none() {
+            return Err(IkkiError::NoSuchImage(name.to_string()));
+        }
+        let dependencies = self.config.dependencies_graph(name);
+        self.scheduled_build(dependencies).await
     }
 
     async fn full_build(&self) -> Result<(), IkkiError> {
-        self.ordered_build(self.config.build_order()).await
+        self.scheduled_build(self.config.dependency_graph()).await
     }
 
     async fn run_dependers(&self, name: &str) -> Result<ContainerIds, IkkiError> {
-        let dependers = self.dependent_images_of(name);
-        self.ordered_run(dependers).await
+        // Rebuilt images get recreated containers, their runtime dependers are only restarted
+        let rebuilt: Vec<ImageName> = self.config.dependers_of(name).concat();
+        let order = self.config.restart_order(name);
+        let restart: Vec<ImageName> = order
+            .iter()
+            .flatten()
+            .filter(|image| !rebuilt.contains(image))
+            .cloned()
+            .collect();
+        self.ordered_run(order, &[], &restart).await
     }
 
-    async fn full_run(&self) -> Result<ContainerIds, IkkiError> {
-        self.ordered_run(self.config.build_order()).await
+    async fn full_run(&self, skip: &[ImageName]) -> Result<ContainerIds, IkkiError> {
+        self.ordered_run(self.config.start_order(), skip, &[]).await
     }
 
     async fn stop_all(&self, ids: ContainerIds) -> Result<(), IkkiError> {
-        for id in ids {
-            docker::remove_container(self.client.clone(), &id).await?;
+        let containers = ids
+            .into_iter()
+            .map(|(image_name, id)| docker::ProjectContainer {
+                id,
+                name: container_name(&self.project, &image_name),
+                service: Some(image_name),
+            })
+            .collect();
+
+        let failures =
+            docker::remove_in_reverse_order(&self.client, &self.config.start_order(), containers)
+                .await;
+
+        // Networks that still have containers attached cannot be removed
+        if !failures.is_empty() {
+            return Err(IkkiError::StopFailures(failures));
+        }
+
+        self.remove_networks().await
+    }
+
+    /// Creates declared volumes and networks, along with the project network.
+    async fn create_resources(&self) -> Result<(), DockerError> {
+        let volumes = self.config.volumes().iter().filter(|vol| !vol.external);
+        for volume in volumes {
+            docker::create_volume(&self.client, &volume_options(volume, &self.config)).await?;
+        }
+
+        let networks = self.config.networks().iter().filter(|net| !net.external);
+        for network in networks {
+            docker::create_network(&self.client, &network_options(network, &self.config)).await?;
+        }
+
+        let project_network = NetworkOptions {
+            name: self.network.clone(),
+            labels: project_labels(&self.project),
+            ..Default::default()
+        };
+        docker::create_network(&self.client, &project_network).await
+    }
+
+    /// Removes the project network and declared networks, volumes are kept.
+    async fn remove_networks(&self) -> Result<(), IkkiError> {
+        let declared = self
+            .config
+            .networks()
+            .iter()
+            .filter(|net| !net.external)
+            .map(|net| self.config.network_name(&net.name));
+
+        for network in declared.chain(std::iter::once(self.network.clone())) {
+            docker::remove_network(&self.client, &network).await?;
         }
         Ok(())
     }
@@ -259,19 +507,20 @@ pub struct BuilderHandle {
 }
 
 impl BuilderHandle {
-    pub fn new(client: Docker, config: IkkiConfig) -> Self {
+    pub fn new(client: Docker, config: IkkiConfig, options: ScheduleOptions) -> Self {
         debug!("setup builder actor");
         let (sender, rx) = mpsc::channel::<Command>(50);
-        let builder = Builder::new(rx, client, config);
+        let builder = Builder::new(rx, client, config, options);
         task::spawn(run_builder(builder));
         debug!("builder actor setup successful");
         BuilderHandle {
             sender,
-            ids: vec![],
+            ids: ContainerIds::new(),
         }
     }
 
-    pub async fn run(&self, name: String) -> Result<(), IkkiError> {
+    /// Runs the services of `name` and its build and runtime dependers, tracking recreated containers
+    pub async fn run(&mut self, name: String) -> Result<(), IkkiError> {
         debug!("builder received run request");
         let (response_tx, response_rx) = oneshot::channel();
         let _ = self.sender.send(Command::Run((name, response_tx))).await;
@@ -280,14 +529,38 @@ impl BuilderHandle {
         match run_result {
             Err(e) => Err(IkkiError::Other(e.to_string())),
             Ok(RunResult::Error(e)) => Err(e),
-            _ => Ok(()),
+            Ok(RunResult::Success(ids)) => {
+                self.ids.extend(ids);
+                Ok(())
+            }
         }
     }
 
-    pub async fn run_all(&mut self) -> Result<(), IkkiError> {
+    /// Starts the exited container of `name` again, recreating it if its configuration changed
+    pub async fn restart(&mut self, name: String) -> Result<(), IkkiError> {
+        debug!("builder received restart request");
+        let (response_tx, response_rx) = oneshot::channel();
+        let _ = self
+            .sender
+            .send(Command::Restart((name, response_tx)))
+            .await;
+        let run_result = response_rx.await;
+        debug!(?run_result, "restart result");
+        match run_result {
+            Err(e) => Err(IkkiError::Other(e.to_string())),
+            Ok(RunResult::Error(e)) => Err(e),
+            Ok(RunResult::Success(ids)) => {
+                self.ids.extend(ids);
+                Ok(())
+            }
+        }
+    }
+
+    /// Runs all services except the ones for images in `skip`
+    pub async fn run_all(&mut self, skip: Vec<ImageName>) -> Result<(), IkkiError> {
         debug!("builder received full run request");
         let (response_tx, response_rx) = oneshot::channel();
-        let _ = self.sender.send(Command::RunAll(response_tx)).await;
+        let _ = self.sender.send(Command::RunAll((skip, response_tx))).await;
         let run_result = response_rx.await;
         debug!(?run_result, "run all result");
         match run_result {
@@ -300,10 +573,14 @@ impl BuilderHandle {
         }
     }
 
-    pub async fn build(&self, name: String) -> Result<(), IkkiError> {
+    /// Builds `name` and its dependers, limited to the dependencies of `target` if given
+    pub async fn build(&self, name: String, target: Option<ImageName>) -> Result<(), IkkiError> {
         debug!("builder received build request");
         let (response_tx, response_rx) = oneshot::channel();
-        let _ = self.sender.send(Command::Build((name, response_tx))).await;
+        let _ = self
+            .sender
+            .send(Command::Build((name, target, response_tx)))
+            .await;
         let build_result = response_rx.await;
         debug!(?build_result, "build result");
         match build_result {
@@ -313,6 +590,22 @@ impl BuilderHandle {
         }
     }
 
+    pub async fn build_with_dependencies(&self, name: String) -> Result<(), IkkiError> {
+        debug!("builder received build with dependencies request");
+        let (response_tx, response_rx) = oneshot::channel();
+        let _ = self
+            .sender
+            .send(Command::BuildWithDependencies((name, response_tx)))
+            .await;
+        let build_result = response_rx.await;
+        debug!(?build_result, "build with dependencies result");
+        match build_result {
+            Err(e) => Err(IkkiError::Other(e.to_string())),
+            Ok(BuildResult::Error(e)) => Err(e),
+            _ => Ok(()),
+        }
+    }
+
     pub async fn build_all(&self) -> Result<(), IkkiError> {
         debug!("builder received full build request");
         let (response_tx, response_rx) = oneshot::channel();
@@ -1,21 +1,29 @@
+use std::num::NonZeroUsize;
+use std::time::Duration;
+
 use bollard::Docker;
-use ikki_config::IkkiConfig;
+use ikki_config::{BuildOrder, IkkiConfig};
 use miette::IntoDiagnostic;
 use tokio::signal;
 use tracing::debug;
 
 use crate::{
-    builder::BuilderHandle,
-    docker::DockerError,
+    args::{BuildCmdArgs, DownOptions, LogsCmdArgs, RemoveImages, SchedulingArgs, UpOptions},
+    builder::{BuilderHandle, ScheduleOptions},
+    console,
+    docker::{self, DockerError},
     docker_config::*,
-    supervisor::{ImageSourceLocations, Mode, SupervisorHandle},
+    explain::explain_wait,
+    logs::{self, LogOptions, LogsHandle},
+    supervisor::{ImageSourceLocations, Mode, Services, SupervisorHandle},
+    IkkiError,
 };
 
 pub async fn explain(config: IkkiConfig) -> miette::Result<()> {
     let build_options = config
         .images()
         .iter()
-        .map(build_options)
+        .map(|img| build_options(&config, img))
         .collect::<Result<Vec<BuildOptions>, DockerError>>()
         .into_diagnostic()?;
 
@@ -25,62 +33,133 @@ pub async fn explain(config: IkkiConfig) -> miette::Result<()> {
         println!("{cmd}");
     }
 
-    let run_options = config
-        .images()
-        .iter()
-        .cloned()
-        .filter(|img| img.service.is_some())
-        .map(|img| {
-            (
-                img.name.clone(),
-                img.pull.unwrap_or(img.name),
-                img.service.unwrap(),
-            )
+    let project = config.project_name();
+    let network = default_network_name(&project);
+
+    // Services grouped by start order, each group waiting on the conditions of its dependencies
+    let run_chunks = config
+        .start_order()
+        .into_iter()
+        .map(|chunk| {
+            chunk
+                .iter()
+                .filter_map(|name| config.find_image(name))
+                .filter(|img| img.service.is_some())
+                .cloned()
+                .map(|img| {
+                    (
+                        img.name.clone(),
+                        img.pull.unwrap_or_else(|| config.image_tag(&img.name)),
+                        img.service.unwrap(),
+                    )
+                })
+                .map(create_run_options)
+                .map(|opt| {
+                    opt.with_resource_names(&config)
+                        .with_default_network(network.clone())
+                        .with_project(&project)
+                })
+                .collect::<Vec<RunOptions>>()
         })
-        .map(create_run_options)
-        .collect::<Vec<RunOptions>>();
+        .filter(|chunk| !chunk.is_empty())
+        .collect::<Vec<_>>();
 
-    let cmds = run_options.into_iter().map(|opt| opt.explain());
+    let volumes = config
+        .volumes()
+        .iter()
+        .filter(|vol| !vol.external)
+        .map(|vol| volume_options(vol, &config));
 
-    for cmd in cmds {
+    for cmd in volumes.map(|opt| opt.explain()) {
         println!("{cmd}");
     }
 
-    Ok(())
-}
+    let mut networks = config
+        .networks()
+        .iter()
+        .filter(|net| !net.external)
+        .map(|net| network_options(net, &config))
+        .collect::<Vec<NetworkOptions>>();
 
-pub async fn up(config: IkkiConfig) -> miette::Result<()> {
-    let docker = Docker::connect_with_local_defaults().into_diagnostic()?;
+    if !run_chunks.is_empty() {
+        networks.push(NetworkOptions {
+            name: network,
+            labels: project_labels(&project),
+            ..Default::default()
+        });
+    }
 
-    debug!("connected to docker daemon");
+    for cmd in networks.into_iter().map(|opt| opt.explain()) {
+        println!("{cmd}");
+    }
+
+    for chunk in run_chunks {
+        let mut conditions = vec![];
+        for opt in &chunk {
+            for condition in config.start_conditions(&opt.alias) {
+                if !conditions.contains(&condition) {
+                    conditions.push(condition);
+                }
+            }
+        }
 
+        let waits = conditions
+            .into_iter()
+            .filter_map(|(dependency, condition)| {
+                explain_wait(&container_name(&project, &dependency), condition)
+            });
+        let cmds = chunk.into_iter().map(|opt| opt.explain());
+
+        for cmd in waits.chain(cmds) {
+            println!("{cmd}");
+        }
+    }
+
+    Ok(())
+}
+
+fn print_build_order(order: &BuildOrder) {
     println!("Calculated image build order:");
     println!();
 
-    for (i, chunk) in config.build_order().iter().enumerate() {
+    for (i, chunk) in order.iter().enumerate() {
         println!("[{}] {}", i + 1, chunk.join(", "));
     }
     println!();
+}
 
-    let image_source_locations: ImageSourceLocations = config
+/// Source directories to watch, only computed with `--watch` because every path must exist.
+fn image_source_locations(
+    config: &IkkiConfig,
+    order: &BuildOrder,
+) -> Result<ImageSourceLocations, IkkiError> {
+    config
         .images()
         .iter()
-        .filter_map(|img| {
-            img.path
-                .clone()
-                .map(|path| (path.canonicalize().unwrap(), img.name.clone()))
+        .filter(|img| order.iter().flatten().any(|name| name == &img.name))
+        .filter_map(|img| img.path.as_ref().map(|path| (img, path)))
+        .map(|(img, path)| {
+            let location = path
+                .canonicalize()
+                .map_err(|source| IkkiError::SourcePath {
+                    image: img.name.clone(),
+                    path: path.clone(),
+                    source,
+                })?;
+            Ok((location, img.name.clone()))
         })
-        .collect();
-
-    let mut builder = BuilderHandle::new(docker.clone(), config);
-
-    builder.build_all().await?;
-    builder.run_all().await?;
-
-    let supervisor = SupervisorHandle::new(image_source_locations, builder, Mode::Run);
+        .collect()
+}
 
-    println!("Watching for source changes...");
+fn schedule_options(args: &SchedulingArgs) -> ScheduleOptions {
+    ScheduleOptions {
+        jobs: args.jobs.map(NonZeroUsize::get),
+        critical_path_first: args.critical_path_first,
+        keep_going: args.keep_going,
+    }
+}
 
+async fn supervise_until_shutdown(supervisor: SupervisorHandle) {
     match signal::ctrl_c().await {
         Ok(()) => {
             debug!("received SIGINT signal, shutting down...");
@@ -94,7 +173,303 @@ pub async fn up(config: IkkiConfig) -> miette::Result<()> {
             eprintln!("unable to listen for shutdown signal: {}", err);
         }
     }
+}
+
+pub async fn build(config: IkkiConfig, opts: BuildCmdArgs) -> miette::Result<()> {
+    let docker = Docker::connect_with_local_defaults().into_diagnostic()?;
+
+    debug!("connected to docker daemon");
+
+    let order = match &opts.name {
+        Some(name) if config.find_image(name).is_none() => {
+            return Err(IkkiError::NoSuchImage(name.clone()).into())
+        }
+        Some(name) => config.dependencies_of(name),
+        None => config.build_order(),
+    };
+
+    print_build_order(&order);
+
+    let image_source_locations = if opts.watch {
+        image_source_locations(&config, &order)?
+    } else {
+        ImageSourceLocations::new()
+    };
+
+    let builder = BuilderHandle::new(docker, config, schedule_options(&opts.scheduling));
+
+    let build_result = match opts.name.clone() {
+        Some(name) => builder.build_with_dependencies(name).await,
+        None => builder.build_all().await,
+    };
+
+    let mut failures = None;
+
+    match build_result {
+        Ok(()) => {
+            let image_count: usize = order.iter().map(Vec::len).sum();
+            println!("Successfully built {} image(s)", image_count);
+        }
+        Err(IkkiError::BuildFailures(report)) => {
+            console::print_build_report(&report);
+            failures = Some(report);
+        }
+        Err(e) => return Err(e.into()),
+    }
+
+    if opts.watch {
+        let mode = Mode::BuildOnly(opts.name);
+        let supervisor = SupervisorHandle::new(image_source_locations, builder, mode);
+
+        println!("Watching for source changes...");
+
+        supervise_until_shutdown(supervisor).await;
+    }
+
+    debug!("all shutdown");
+
+    match failures {
+        Some(report) => Err(IkkiError::BuildFailures(report).into()),
+        None => Ok(()),
+    }
+}
+
+pub async fn up(config: IkkiConfig, opts: UpOptions) -> miette::Result<()> {
+    let docker = Docker::connect_with_local_defaults().into_diagnostic()?;
+
+    debug!("connected to docker daemon");
+
+    let order = config.build_order();
+
+    print_build_order(&order);
+
+    let image_source_locations = if opts.watch {
+        image_source_locations(&config, &order)?
+    } else {
+        ImageSourceLocations::new()
+    };
+
+    let services = Services {
+        docker: docker.clone(),
+        project: config.project_name(),
+        restart_policies: config
+            .images()
+            .iter()
+            .filter_map(|img| Some((img.name.clone(), img.service.as_ref()?.restart)))
+            .collect(),
+        stop_timeouts: config
+            .images()
+            .iter()
+            .filter_map(|img| {
+                let timeout = img.service.as_ref()?.stop_timeout?;
+                Some((img.name.clone(), Duration::from_secs(timeout.into())))
+            })
+            .collect(),
+    };
+
+    let service_names: Vec<String> = config
+        .start_order()
+        .concat()
+        .into_iter()
+        .filter(|name| {
+            config
+                .find_image(name)
+                .is_some_and(|img| img.service.is_some())
+        })
+        .collect();
+    let project = config.project_name();
+    let logs_docker = docker.clone();
+
+    let mut builder = BuilderHandle::new(docker, config, schedule_options(&opts.scheduling));
+
+    let mut failures = None;
+
+    let unavailable = match builder.build_all().await {
+        Ok(()) => vec![],
+        Err(IkkiError::BuildFailures(report)) => {
+            console::print_build_report(&report);
+            let unavailable = report.unavailable();
+            failures = Some(report);
+            unavailable
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    // Existing containers that are kept only show what they write from now on
+    let since = logs::now();
+
+    builder.run_all(unavailable).await?;
+
+    let log_options = LogOptions {
+        follow: true,
+        since,
+        prefix: true,
+        ..Default::default()
+    };
+    let logs = LogsHandle::new(logs_docker, project, service_names, log_options);
+
+    if opts.watch {
+        let supervisor =
+            SupervisorHandle::new(image_source_locations, builder, Mode::Run(services));
+
+        println!("Watching for source changes...");
+
+        supervise_until_shutdown(supervisor).await;
+    } else {
+        println!("Services are running, press Ctrl-C to stop them");
+
+        match signal::ctrl_c().await {
+            Ok(()) => {
+                debug!("received SIGINT signal, shutting down...");
+                builder.stop_all().await?;
+            }
+            Err(err) => {
+                eprintln!("unable to listen for shutdown signal: {}", err);
+            }
+        }
+    }
+
+    logs.shutdown().await;
 
     debug!("all shutdown");
+
+    match failures {
+        Some(report) => Err(IkkiError::BuildFailures(report).into()),
+        None => Ok(()),
+    }
+}
+
+pub async fn down(config: IkkiConfig, opts: DownOptions) -> miette::Result<()> {
+    let docker = Docker::connect_with_local_defaults().into_diagnostic()?;
+
+    debug!("connected to docker daemon");
+
+    let project = config.project_name();
+
+    let containers = docker::project_containers(&docker, &project)
+        .await
+        .into_diagnostic()?;
+    let failures =
+        docker::remove_in_reverse_order(&docker, &config.start_order(), containers).await;
+    if !failures.is_empty() {
+        return Err(IkkiError::StopFailures(failures).into());
+    }
+
+    for network in docker::project_networks(&docker, &project)
+        .await
+        .into_diagnostic()?
+    {
+        docker::remove_network(&docker, &network)
+            .await
+            .into_diagnostic()?;
+        println!("Removed network {}", network);
+    }
+
+    if opts.volumes {
+        for volume in docker::project_volumes(&docker, &project)
+            .await
+            .into_diagnostic()?
+        {
+            docker::remove_volume(&docker, &volume)
+                .await
+                .into_diagnostic()?;
+            println!("Removed volume {}", volume);
+        }
+    }
+
+    if let Some(scope) = opts.rmi {
+        let mut images = vec![];
+        for img in config.images() {
+            match (&img.pull, scope) {
+                (None, _) => {
+                    let build_opts = build_options(&config, img).into_diagnostic()?;
+                    images.extend(build_opts.alias);
+                    images.push(build_opts.tag);
+                }
+                (Some(pull), RemoveImages::All) => images.push(pull.clone()),
+                (Some(_), RemoveImages::Local) => {}
+            }
+        }
+
+        let mut failures = vec![];
+        for image in images {
+            match docker::remove_image(&docker, &image).await {
+                Ok(()) => println!("Removed image {}", image),
+                Err(e) => {
+                    println!("Failed to remove image {}: {}", image, e);
+                    failures.push((image, e));
+                }
+            }
+        }
+        if !failures.is_empty() {
+            return Err(IkkiError::RemoveImageFailures(failures).into());
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn logs(config: IkkiConfig, opts: LogsCmdArgs) -> miette::Result<()> {
+    let docker = Docker::connect_with_local_defaults().into_diagnostic()?;
+
+    debug!("connected to docker daemon");
+
+    let project = config.project_name();
+
+    for name in &opts.services {
+        if config
+            .find_image(name)
+            .and_then(|img| img.service.as_ref())
+            .is_none()
+        {
+            return Err(IkkiError::NoSuchService(name.clone()).into());
+        }
+    }
+
+    let services = if opts.services.is_empty() {
+        // Without `--follow`, only services that have a container have output to show
+        let started: Vec<String> = docker::project_containers(&docker, &project)
+            .await
+            .into_diagnostic()?
+            .into_iter()
+            .filter_map(|container| container.service)
+            .collect();
+
+        config
+            .start_order()
+            .concat()
+            .into_iter()
+            .filter(|name| {
+                config
+                    .find_image(name)
+                    .is_some_and(|img| img.service.is_some())
+            })
+            .filter(|name| opts.follow || started.contains(name))
+            .collect()
+    } else {
+        opts.services
+    };
+
+    let log_options = LogOptions {
+        follow: opts.follow,
+        tail: opts.tail,
+        since: opts.since.unwrap_or_default(),
+        timestamps: opts.timestamps,
+        prefix: !opts.no_prefix,
+    };
+    let logs = LogsHandle::new(docker, project, services, log_options);
+
+    if !opts.follow {
+        logs.wait().await;
+        return Ok(());
+    }
+
+    match signal::ctrl_c().await {
+        Ok(()) => debug!("received SIGINT signal, shutting down..."),
+        Err(err) => eprintln!("unable to listen for shutdown signal: {}", err),
+    }
+
+    logs.shutdown().await;
+
     Ok(())
 }
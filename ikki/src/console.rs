@@ -1,5 +1,7 @@
 use indicatif::{ProgressBar, ProgressStyle};
 
+use crate::builder::BuildReport;
+
 pub fn default_pull_progress_bar() -> ProgressBar {
     let style =
         ProgressStyle::with_template("[{bar:60.cyan/blue}] ({bytes}/{total_bytes}) {wide_msg}")
@@ -18,3 +20,46 @@ pub fn default_build_progress_bar() -> ProgressBar {
     pb.set_style(spinner_style);
     pb
 }
+
+pub fn print_build_report(report: &BuildReport) {
+    let failed = report
+        .failed
+        .iter()
+        .map(|(name, err)| ("failed", name, err.to_string()));
+    let skipped = report.skipped.iter().map(|(name, dependency)| {
+        let reason = format!("depends on failed image `{}`", dependency);
+        ("skipped", name, reason)
+    });
+    let succeeded = report
+        .succeeded
+        .iter()
+        .map(|name| ("succeeded", name, String::new()));
+    let rows: Vec<_> = succeeded.chain(failed).chain(skipped).collect();
+
+    let width = rows
+        .iter()
+        .map(|(_, name, _)| name.len())
+        .chain(std::iter::once("IMAGE".len()))
+        .max()
+        .unwrap_or_default();
+
+    println!();
+    println!("Build summary:");
+    println!();
+    println!(
+        "{:<9}  {:<width$}  DETAILS",
+        "STATUS",
+        "IMAGE",
+        width = width
+    );
+    for (status, name, details) in rows {
+        println!(
+            "{:<9}  {:<width$}  {}",
+            status,
+            name,
+            details,
+            width = width
+        );
+    }
+    println!();
+}
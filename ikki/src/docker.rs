@@ -1,66 +1,290 @@
 use crate::{console, docker_config::*};
 use bollard::{
-    container::CreateContainerOptions,
-    image::{BuildImageOptions, CreateImageOptions},
+    container::{
+        Config, CreateContainerOptions, DownloadFromContainerOptions, ListContainersOptions,
+        RemoveContainerOptions,
+    },
+    errors::Error as BollardError,
+    image::{BuildImageOptions, CreateImageOptions, TagImageOptions},
+    models::HealthStatusEnum,
+    network::{
+        ConnectNetworkOptions, CreateNetworkOptions, InspectNetworkOptions, ListNetworksOptions,
+    },
+    volume::{CreateVolumeOptions, ListVolumesOptions},
     Docker,
 };
-use futures::StreamExt;
-use futures_util::TryStreamExt;
+use futures::{future, StreamExt};
 use ikki_config::*;
 use indicatif::{MultiProgress, ProgressBar};
 use std::{
-    collections::{HashMap, HashSet},
+    collections::HashMap,
     io::{self, Write},
+    path::{Component, Path, PathBuf},
+    process::Stdio,
+    time::Duration,
 };
 use tar::Builder;
 use thiserror::Error;
-use tokio::task;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    process, task,
+};
 use tracing::debug;
 
 static STATUS_DOWNLOADING: &str = "Downloading";
+static DEFAULT_DOCKERFILE: &str = "Dockerfile";
+/// Name under which a Dockerfile from outside of the build context is added to the archive
+static EXTERNAL_DOCKERFILE: &str = ".ikki.Dockerfile";
 
 #[derive(Error, Debug)]
 pub enum DockerError {
     #[error("Invalid settings: {0}")]
     Settings(String),
-    #[error("Failed to archive a directory")]
+    #[error("Failed to archive a directory: {0}")]
     Archive(String),
+    #[error("Failed to export build output: {0}")]
+    Output(String),
+    #[error("Image build failed: {0}")]
+    BuildFailed(String),
+    #[error("Image pull failed: {0}")]
+    PullFailed(String),
+    #[error("Dependency condition not met: {0}")]
+    UnmetCondition(String),
     #[error("Docker daemon error: {0}")]
     DockerDaemonError(#[from] bollard::errors::Error),
 }
 
+/// Returns the path of the Dockerfile relative to the context root, adding it to
+/// the archive first if it lives outside of the context directory.
+fn append_dockerfile<W: Write>(
+    tar: &mut Builder<W>,
+    context: &Path,
+    file: &Path,
+) -> io::Result<String> {
+    let context = context.canonicalize()?;
+    let file = file.canonicalize()?;
+
+    match file.strip_prefix(&context) {
+        Ok(relative) => Ok(relative
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/")),
+        Err(_) => {
+            tar.append_path_with_name(&file, EXTERNAL_DOCKERFILE)?;
+            Ok(EXTERNAL_DOCKERFILE.to_string())
+        }
+    }
+}
+
+/// Archives the build context, leaving out `exclude` (typically a build secret) if it lives
+/// inside of the context directory.
+fn append_context<W: Write>(
+    tar: &mut Builder<W>,
+    context: &Path,
+    exclude: Option<&Path>,
+) -> io::Result<()> {
+    let context = context.canonicalize()?;
+
+    match exclude {
+        Some(exclude) if exclude.starts_with(&context) => {
+            append_dir_filtered(tar, &context, Path::new(""), exclude)
+        }
+        _ => tar.append_dir_all("", &context),
+    }
+}
+
+fn append_dir_filtered<W: Write>(
+    tar: &mut Builder<W>,
+    dir: &Path,
+    relative: &Path,
+    exclude: &Path,
+) -> io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path == exclude {
+            continue;
+        }
+
+        let name = relative.join(path.file_name().unwrap_or_default());
+        if path.is_dir() {
+            tar.append_dir(&name, &path)?;
+            append_dir_filtered(tar, &path, &name, exclude)?;
+        } else {
+            tar.append_path_with_name(&path, &name)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Daemon address `Docker::connect_with_local_defaults` connects to, so that the Docker CLI
+/// talks to the same daemon regardless of its configured context.
+fn local_docker_host() -> String {
+    #[cfg(unix)]
+    return std::env::var("DOCKER_HOST")
+        .ok()
+        .filter(|host| host.starts_with("unix://"))
+        .unwrap_or_else(|| "unix:///var/run/docker.sock".to_string());
+    #[cfg(windows)]
+    return "npipe:////./pipe/docker_engine".to_string();
+}
+
+/// Builds with BuildKit through the Docker CLI, which serves the secret to the build over a
+/// session. The context archive is passed on stdin so it is the same one an API build would use.
+async fn buildkit_build(
+    tar: Vec<u8>,
+    dockerfile: &str,
+    tag: &str,
+    build_args: &HashMap<String, String>,
+    secret: &Secret,
+    secret_src: &Path,
+    pb: &ProgressBar,
+) -> Result<(), DockerError> {
+    let mut cmd = process::Command::new("docker");
+    cmd.env("DOCKER_BUILDKIT", "1")
+        .env("DOCKER_HOST", local_docker_host())
+        .env_remove("DOCKER_CONTEXT")
+        .arg("build")
+        .arg("--progress")
+        .arg("plain")
+        .arg("--file")
+        .arg(dockerfile)
+        .arg("--tag")
+        .arg(tag)
+        .arg("--secret")
+        .arg(format!("id={},src={}", secret.id, secret_src.display()));
+
+    for (name, value) in build_args {
+        cmd.arg("--build-arg").arg(format!("{}={}", name, value));
+    }
+
+    let mut child = cmd
+        .arg("-")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => DockerError::BuildFailed(
+                "images with a secret are built with the `docker` CLI, which was not found in PATH"
+                    .to_string(),
+            ),
+            _ => DockerError::BuildFailed(format!("failed to run the docker CLI: {}", e)),
+        })?;
+
+    let mut stdin = child
+        .stdin
+        .take()
+        .expect("stdin of the docker CLI is piped");
+    let write_context = async move { stdin.write_all(&tar).await };
+
+    // Plain progress goes to stderr, one line per step update
+    let stderr = child
+        .stderr
+        .take()
+        .expect("stderr of the docker CLI is piped");
+    let message = pb.message();
+    let read_progress = async {
+        let mut output = String::new();
+        let mut lines = BufReader::new(stderr).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            if line.starts_with('#') {
+                pb.set_message(format!("{}: {}", message, line));
+            }
+            pb.tick();
+            output.push_str(&line);
+            output.push('\n');
+        }
+        output
+    };
+
+    let (written, output, status) = tokio::join!(write_context, read_progress, child.wait());
+    let status = status.map_err(|e| DockerError::BuildFailed(e.to_string()))?;
+
+    if !status.success() {
+        return Err(DockerError::BuildFailed(output.trim().to_string()));
+    }
+
+    written.map_err(|e| DockerError::Archive(e.to_string()))
+}
+
 pub async fn build_image(
     docker: Docker,
     image: Image,
+    build_opts: BuildOptions,
     mp: MultiProgress,
 ) -> Result<(), DockerError> {
-    debug!("building {}...", image.name);
+    debug!("building {} as {}...", image.name, build_opts.tag);
 
     let pb = mp.add(console::default_build_progress_bar());
     pb.set_message(image.name.clone());
 
-    let build_opts = build_options(&image)?;
-
     let build_path = build_opts.path.ok_or(DockerError::Settings(format!(
         "missing image build path for image `{}`",
         image.name
     )))?;
 
-    let archive_task = task::spawn_blocking(|| {
+    let secret_src = match &build_opts.secret {
+        Some(secret) => Some(secret.src.canonicalize().map_err(|e| {
+            DockerError::Settings(format!(
+                "secret `{}` of image `{}` is not readable at {}: {}",
+                secret.id,
+                image.name,
+                secret.src.display(),
+                e
+            ))
+        })?),
+        None => None,
+    };
+
+    let dockerfile_path = match &build_opts.file {
+        Some(file) => Some(file.canonicalize().map_err(|e| {
+            DockerError::Settings(format!(
+                "Dockerfile of image `{}` could not be found at {}: {}",
+                image.name,
+                file.display(),
+                e
+            ))
+        })?),
+        None => None,
+    };
+    let excluded = secret_src.clone();
+    let archive_task = task::spawn_blocking(move || {
         let mut buf = vec![];
         let mut tar = Builder::new(&mut buf);
-        tar.append_dir_all("", build_path)?;
-        tar.into_inner().cloned()
+        append_context(&mut tar, &build_path, excluded.as_deref())?;
+        let dockerfile = match dockerfile_path {
+            Some(file) => append_dockerfile(&mut tar, &build_path, &file)?,
+            None => DEFAULT_DOCKERFILE.to_string(),
+        };
+        tar.into_inner().cloned().map(|tar| (tar, dockerfile))
     });
 
-    let tar = archive_task
+    let (tar, dockerfile) = archive_task
         .await
         .map(|res| res.map_err(|e| DockerError::Archive(e.to_string())))
         .map_err(|e| DockerError::Archive(e.to_string()))??;
 
+    if let (Some(secret), Some(secret_src)) = (&build_opts.secret, &secret_src) {
+        pb.enable_steady_tick(Duration::from_millis(100));
+        let result = buildkit_build(
+            tar,
+            &dockerfile,
+            &build_opts.tag,
+            &build_opts.build_args,
+            secret,
+            secret_src,
+            &pb,
+        )
+        .await;
+        pb.finish_and_clear();
+        return result;
+    }
+
     let build_options = BuildImageOptions {
-        dockerfile: "Dockerfile".to_string(),
-        t: image.name.clone(),
+        dockerfile,
+        t: build_opts.tag.clone(),
         buildargs: build_opts.build_args,
         rm: true,
         ..Default::default()
@@ -75,6 +299,14 @@ pub async fn build_image(
     while let Some(info) = build_stream.next().await {
         let info = info?;
 
+        if let Some(error) = info.error {
+            if let Some(dl_pb) = dl_pb {
+                dl_pb.finish_and_clear();
+            }
+            pb.finish_and_clear();
+            return Err(DockerError::BuildFailed(error.trim().to_string()));
+        }
+
         if let Some(status) = info.status {
             if status == STATUS_DOWNLOADING {
                 if dl_pb.is_none() {
@@ -118,6 +350,167 @@ pub async fn build_image(
     Ok(())
 }
 
+/// Adds `alias` as another tag of `image_name`.
+pub async fn tag_image(docker: &Docker, image_name: &str, alias: &str) -> Result<(), DockerError> {
+    let options = TagImageOptions {
+        repo: alias,
+        tag: "latest",
+    };
+    docker.tag_image(image_name, Some(options)).await?;
+
+    debug!("tagged {} as {}", image_name, alias);
+    Ok(())
+}
+
+/// Copies `src` (or the image working directory) out of a throwaway container into `output`.
+pub async fn export_output(
+    docker: Docker,
+    image_name: &str,
+    src: Option<PathBuf>,
+    output: PathBuf,
+) -> Result<(), DockerError> {
+    let src = match src {
+        Some(src) => src.display().to_string(),
+        None => docker
+            .inspect_image(image_name)
+            .await?
+            .config
+            .and_then(|config| config.working_dir)
+            .filter(|dir| !dir.is_empty())
+            .ok_or_else(|| {
+                DockerError::Settings(format!(
+                    "image `{}` has no WORKDIR to export, set `output-src` to the directory to export",
+                    image_name
+                ))
+            })?,
+    };
+
+    debug!(
+        "exporting {} from {} to {}",
+        src,
+        image_name,
+        output.display()
+    );
+
+    let config = Config {
+        image: Some(image_name.to_string()),
+        ..Default::default()
+    };
+    let id = docker
+        .create_container::<String, String>(None, config)
+        .await?
+        .id;
+
+    let mut archive = vec![];
+    let mut download_stream =
+        docker.download_from_container(&id, Some(DownloadFromContainerOptions { path: src }));
+    let download = async {
+        while let Some(chunk) = download_stream.next().await {
+            archive.extend_from_slice(&chunk?);
+        }
+        Ok::<_, DockerError>(())
+    }
+    .await;
+
+    let remove_options = RemoveContainerOptions {
+        force: true,
+        ..Default::default()
+    };
+    docker.remove_container(&id, Some(remove_options)).await?;
+    download?;
+
+    let unpack_output = output.clone();
+    task::spawn_blocking(move || unpack_output_archive(&archive, &unpack_output))
+        .await
+        .map_err(|e| DockerError::Output(e.to_string()))?
+        .map_err(|e| DockerError::Output(e.to_string()))?;
+
+    println!(
+        "Exported output of `{}` to {}",
+        image_name,
+        output.display()
+    );
+
+    Ok(())
+}
+
+/// Unpacks the contents of an archived directory (or a single archived file) into `output`.
+fn unpack_output_archive(archive: &[u8], output: &Path) -> io::Result<()> {
+    std::fs::create_dir_all(output)?;
+
+    let mut archive = tar::Archive::new(archive);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+
+        // Hard links may point at any file on the host
+        if entry.header().entry_type().is_hard_link() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("archive entry `{}` is a hard link", path.display()),
+            ));
+        }
+
+        if path
+            .components()
+            .any(|component| !matches!(component, Component::Normal(_)))
+        {
+            continue;
+        }
+
+        // The archive root is the exported directory itself, unless a single file was exported
+        let relative: PathBuf = if path.components().count() > 1 {
+            path.components().skip(1).collect()
+        } else if entry.header().entry_type().is_dir() {
+            continue;
+        } else {
+            path
+        };
+
+        ensure_no_symlinks(output, &relative)?;
+
+        let destination = output.join(relative);
+        if let Some(parent) = destination.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        // Replace symlinks left by a previous export instead of writing through them
+        if destination
+            .symlink_metadata()
+            .is_ok_and(|metadata| metadata.file_type().is_symlink())
+        {
+            std::fs::remove_file(&destination)?;
+        }
+        entry.unpack(destination)?;
+    }
+
+    Ok(())
+}
+
+/// Fails if a directory between `output` and `output/relative` is a symlink, which an
+/// earlier archive entry could have pointed outside of `output`.
+fn ensure_no_symlinks(output: &Path, relative: &Path) -> io::Result<()> {
+    let mut current = output.to_path_buf();
+    let parents = relative.parent().into_iter().flat_map(Path::components);
+    for component in parents {
+        current.push(component);
+        let is_symlink = current
+            .symlink_metadata()
+            .map(|metadata| metadata.file_type().is_symlink())
+            .unwrap_or(false);
+        if is_symlink {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "archive entry `{}` would be written through the symlink `{}`",
+                    relative.display(),
+                    current.display()
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
 pub async fn pull_image(
     docker: Docker,
     image: Image,
@@ -152,6 +545,11 @@ pub async fn pull_image(
     while let Some(info) = pull_stream.next().await {
         let info = info?;
 
+        if let Some(error) = info.error {
+            pb.finish_and_clear();
+            return Err(DockerError::PullFailed(error.trim().to_string()));
+        }
+
         if let Some(status) = info.status {
             if status == STATUS_DOWNLOADING {
                 let detail = info
@@ -183,19 +581,174 @@ pub async fn pull_image(
     Ok(())
 }
 
+pub fn is_not_found(error: &BollardError) -> bool {
+    matches!(
+        error,
+        BollardError::DockerResponseServerError {
+            status_code: 404,
+            ..
+        }
+    )
+}
+
+/// Creates the volume unless it already exists.
+pub async fn create_volume(docker: &Docker, options: &VolumeOptions) -> Result<(), DockerError> {
+    match docker.inspect_volume(&options.name).await {
+        Ok(_) => return Ok(()),
+        Err(e) if is_not_found(&e) => {}
+        Err(e) => return Err(e.into()),
+    }
+
+    docker
+        .create_volume(CreateVolumeOptions {
+            name: options.name.clone(),
+            driver: options.driver.clone().unwrap_or_default(),
+            driver_opts: options.options.clone(),
+            labels: options.labels.clone(),
+        })
+        .await?;
+
+    debug!("created volume {}", options.name);
+    Ok(())
+}
+
+/// Creates the network unless it already exists.
+pub async fn create_network(docker: &Docker, options: &NetworkOptions) -> Result<(), DockerError> {
+    match docker
+        .inspect_network(&options.name, None::<InspectNetworkOptions<String>>)
+        .await
+    {
+        Ok(_) => return Ok(()),
+        Err(e) if is_not_found(&e) => {}
+        Err(e) => return Err(e.into()),
+    }
+
+    docker
+        .create_network(CreateNetworkOptions {
+            name: options.name.clone(),
+            check_duplicate: true,
+            driver: options.driver.clone().unwrap_or_default(),
+            internal: options.internal,
+            options: options.options.clone(),
+            labels: options.labels.clone(),
+            ..Default::default()
+        })
+        .await?;
+
+    debug!("created network {}", options.name);
+    Ok(())
+}
+
+/// Removes the network, doing nothing if it is already gone.
+pub async fn remove_network(docker: &Docker, name: &str) -> Result<(), DockerError> {
+    match docker.remove_network(name).await {
+        Ok(()) => {
+            debug!("removed network {}", name);
+            Ok(())
+        }
+        Err(e) if is_not_found(&e) => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Existing container that can be kept, along with whether it is running. A container
+/// with a different configuration hash or image is removed instead.
+async fn reusable_container(
+    docker: &Docker,
+    container_name: &str,
+    image_name: &str,
+    config_hash: &str,
+) -> Result<Option<(String, bool)>, DockerError> {
+    let container = match docker.inspect_container(container_name, None).await {
+        Ok(container) => container,
+        Err(e) if is_not_found(&e) => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let id = container.id.unwrap_or_else(|| container_name.to_string());
+
+    let image_id = docker.inspect_image(image_name).await?.id;
+    let same_hash = container
+        .config
+        .and_then(|config| config.labels)
+        .and_then(|mut labels| labels.remove(CONFIG_HASH_LABEL))
+        .is_some_and(|hash| hash == config_hash);
+    let same_image = image_id.is_some() && container.image == image_id;
+
+    if same_hash && same_image {
+        let running = container
+            .state
+            .and_then(|state| state.running)
+            .unwrap_or(false);
+        return Ok(Some((id, running)));
+    }
+
+    debug!("container {} is stale, recreating it", container_name);
+    remove_container(docker.clone(), &id).await?;
+    Ok(None)
+}
+
+/// Starts a container for `options`, keeping an existing container with the same
+/// configuration and image and recreating one that differs. A kept container that is
+/// already running is restarted if `restart` is set.
 pub async fn run(
     docker: Docker,
-    container_name: String,
-    image_name: String,
-    service: Service,
+    options: RunOptions,
+    restart: bool,
 ) -> Result<String, DockerError> {
-    let options = CreateContainerOptions {
-        name: container_name.to_string(),
-    };
+    let container_name = options.container_name.clone();
+    let image_name = options.image_name.clone();
+    let connected_networks: Vec<String> = options.connected_networks().cloned().collect();
+    let mut config = create_container_config(options);
 
-    let config = create_container_config(&container_name, &image_name, service);
+    let hash = config_hash(&config, &connected_networks);
+    config
+        .labels
+        .get_or_insert_with(HashMap::new)
+        .insert(CONFIG_HASH_LABEL.to_string(), hash.clone());
 
-    let id = docker.create_container(Some(options), config).await?.id;
+    match reusable_container(&docker, &container_name, &image_name, &hash).await? {
+        Some((id, true)) if restart => {
+            docker.restart_container(&id, None).await?;
+            println!("Restarted container {} ({})", container_name, id);
+            return Ok(id);
+        }
+        Some((id, true)) => {
+            println!("Container {} is up-to-date", container_name);
+            return Ok(id);
+        }
+        Some((id, false)) => {
+            docker.start_container::<String>(&id, None).await?;
+            println!("Started existing container {} ({})", container_name, id);
+            return Ok(id);
+        }
+        None => {}
+    }
+
+    let create_options = CreateContainerOptions {
+        name: container_name.clone(),
+    };
+    let id = docker
+        .create_container(Some(create_options), config)
+        .await?
+        .id;
+
+    for network in connected_networks {
+        let options = ConnectNetworkOptions {
+            container: id.as_str(),
+            ..Default::default()
+        };
+        if let Err(e) = docker.connect_network(&network, options).await {
+            // Left behind, the container would be reused as identical without this network
+            let remove_options = RemoveContainerOptions {
+                force: true,
+                ..Default::default()
+            };
+            if let Err(e) = docker.remove_container(&id, Some(remove_options)).await {
+                debug!("failed to remove container {}: {}", container_name, e);
+            }
+            return Err(e.into());
+        }
+    }
     docker.start_container::<String>(&id, None).await?;
 
     println!("Started container {} ({})", container_name, id);
@@ -204,9 +757,422 @@ pub async fn run(
     Ok(id)
 }
 
+/// Whether the container still exists and is not running, as opposed to removed or started again.
+pub async fn container_exited(docker: &Docker, id: &str) -> Result<bool, DockerError> {
+    match docker.inspect_container(id, None).await {
+        Ok(container) => Ok(!container
+            .state
+            .and_then(|state| state.running)
+            .unwrap_or(false)),
+        Err(e) if is_not_found(&e) => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Polls the container until it meets `condition`, failing once it no longer can or
+/// when `timeout` elapses first.
+pub async fn wait_for_condition(
+    docker: Docker,
+    container_name: String,
+    condition: DependencyCondition,
+    timeout: Duration,
+) -> Result<(), DockerError> {
+    if condition == DependencyCondition::Started {
+        return Ok(());
+    }
+
+    println!("Waiting for {} to be {}", container_name, condition);
+
+    tokio::time::timeout(timeout, poll_condition(&docker, &container_name, condition))
+        .await
+        .unwrap_or_else(|_| {
+            Err(DockerError::UnmetCondition(format!(
+                "container {} was not {} after {}s",
+                container_name,
+                condition,
+                timeout.as_secs()
+            )))
+        })
+}
+
+async fn poll_condition(
+    docker: &Docker,
+    container_name: &str,
+    condition: DependencyCondition,
+) -> Result<(), DockerError> {
+    loop {
+        let state = docker
+            .inspect_container(container_name, None)
+            .await?
+            .state
+            .unwrap_or_default();
+        let running = state.running.unwrap_or(false);
+        let exit_code = state.exit_code.unwrap_or_default();
+        let health = state.health.and_then(|health| health.status);
+
+        match condition {
+            DependencyCondition::Healthy => match health {
+                Some(HealthStatusEnum::HEALTHY) => return Ok(()),
+                Some(HealthStatusEnum::UNHEALTHY) => {
+                    return Err(DockerError::UnmetCondition(format!(
+                        "container {} is unhealthy",
+                        container_name
+                    )))
+                }
+                _ if !running => {
+                    return Err(DockerError::UnmetCondition(format!(
+                        "container {} exited with code {} before becoming healthy",
+                        container_name, exit_code
+                    )))
+                }
+                _ => {}
+            },
+            DependencyCondition::CompletedSuccessfully if !running => {
+                return match exit_code {
+                    0 => Ok(()),
+                    code => Err(DockerError::UnmetCondition(format!(
+                        "container {} exited with code {}",
+                        container_name, code
+                    ))),
+                }
+            }
+            _ => {}
+        }
+
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+}
+
 pub async fn remove_container(docker: Docker, id: &str) -> Result<(), DockerError> {
     docker.stop_container(id, None).await?;
     docker.remove_container(id, None).await?;
 
     Ok(())
 }
+
+/// Stops and removes containers in reverse dependency order, the containers of a chunk
+/// concurrently. Containers of images missing from `order` go first. Failures are printed
+/// and returned without aborting the remaining removals.
+pub async fn remove_in_reverse_order(
+    docker: &Docker,
+    order: &BuildOrder,
+    mut containers: Vec<ProjectContainer>,
+) -> Vec<(String, DockerError)> {
+    let mut chunks: Vec<Vec<ProjectContainer>> = vec![];
+
+    for chunk in order.iter().rev() {
+        let (current, rest) = containers.into_iter().partition(|container| {
+            container
+                .service
+                .as_ref()
+                .is_some_and(|service| chunk.contains(service))
+        });
+        chunks.push(current);
+        containers = rest;
+    }
+    chunks.insert(0, containers);
+
+    let mut failures = vec![];
+
+    for chunk in chunks {
+        let removals = chunk.into_iter().map(|container| async move {
+            match remove_container(docker.clone(), &container.id).await {
+                Ok(()) => {
+                    println!("Removed container {}", container.name);
+                    None
+                }
+                Err(e) => {
+                    println!("Failed to remove container {}: {}", container.name, e);
+                    Some((container.name, e))
+                }
+            }
+        });
+
+        failures.extend(future::join_all(removals).await.into_iter().flatten());
+    }
+
+    failures
+}
+
+/// A container found by its project label
+#[derive(Debug)]
+pub struct ProjectContainer {
+    pub id: String,
+    pub name: String,
+    /// Image name of the service the container was started for
+    pub service: Option<String>,
+}
+
+fn project_filter(project: &str) -> HashMap<String, Vec<String>> {
+    HashMap::from([(
+        "label".to_string(),
+        vec![format!("{}={}", PROJECT_LABEL, project)],
+    )])
+}
+
+/// Containers of `project`, including stopped ones.
+pub async fn project_containers(
+    docker: &Docker,
+    project: &str,
+) -> Result<Vec<ProjectContainer>, DockerError> {
+    let options = ListContainersOptions {
+        all: true,
+        filters: project_filter(project),
+        ..Default::default()
+    };
+
+    let containers = docker
+        .list_containers(Some(options))
+        .await?
+        .into_iter()
+        .filter_map(|container| {
+            let id = container.id?;
+            let name = container
+                .names
+                .and_then(|names| names.into_iter().next())
+                .map(|name| name.trim_start_matches('/').to_string())
+                .unwrap_or_else(|| id.clone());
+            let service = container
+                .labels
+                .and_then(|mut labels| labels.remove(SERVICE_LABEL));
+            Some(ProjectContainer { id, name, service })
+        })
+        .collect();
+
+    Ok(containers)
+}
+
+/// ID of the container of `service` in `project`, found by their labels. Stopped
+/// containers are only considered if `running` is not set.
+pub async fn service_container(
+    docker: &Docker,
+    project: &str,
+    service: &str,
+    running: bool,
+) -> Result<Option<String>, DockerError> {
+    let mut filters = project_filter(project);
+    filters
+        .entry("label".to_string())
+        .or_default()
+        .push(format!("{}={}", SERVICE_LABEL, service));
+    let options = ListContainersOptions {
+        all: !running,
+        filters,
+        ..Default::default()
+    };
+
+    let id = docker
+        .list_containers(Some(options))
+        .await?
+        .into_iter()
+        .find_map(|container| container.id);
+
+    Ok(id)
+}
+
+/// Networks created by Ikki for `project`.
+pub async fn project_networks(docker: &Docker, project: &str) -> Result<Vec<String>, DockerError> {
+    let options = ListNetworksOptions {
+        filters: project_filter(project),
+    };
+
+    let networks = docker
+        .list_networks(Some(options))
+        .await?
+        .into_iter()
+        .filter_map(|network| network.name)
+        .collect();
+
+    Ok(networks)
+}
+
+/// Volumes created by Ikki for `project`.
+pub async fn project_volumes(docker: &Docker, project: &str) -> Result<Vec<String>, DockerError> {
+    let options = ListVolumesOptions {
+        filters: project_filter(project),
+    };
+
+    let volumes = docker
+        .list_volumes(Some(options))
+        .await?
+        .volumes
+        .unwrap_or_default()
+        .into_iter()
+        .map(|volume| volume.name)
+        .collect();
+
+    Ok(volumes)
+}
+
+/// Removes the volume, doing nothing if it does not exist.
+pub async fn remove_volume(docker: &Docker, name: &str) -> Result<(), DockerError> {
+    match docker.remove_volume(name, None).await {
+        Ok(()) => {
+            debug!("removed volume {}", name);
+            Ok(())
+        }
+        Err(e) if is_not_found(&e) => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Removes the image, doing nothing if it does not exist.
+pub async fn remove_image(docker: &Docker, name: &str) -> Result<(), DockerError> {
+    match docker.remove_image(name, None, None).await {
+        Ok(_) => {
+            debug!("removed image {}", name);
+            Ok(())
+        }
+        Err(e) if is_not_found(&e) => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tar::{EntryType, Header};
+
+    /// Empty directory under the system temp directory, unique to the test
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ikki-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Appends an entry with a raw `path`, which `Header::set_path` would reject for `..`
+    fn append_entry(
+        tar: &mut Builder<Vec<u8>>,
+        path: &str,
+        entry_type: EntryType,
+        link: Option<&Path>,
+        data: &[u8],
+    ) {
+        let mut header = Header::new_gnu();
+        let name = &mut header.as_gnu_mut().unwrap().name;
+        name[..path.len()].copy_from_slice(path.as_bytes());
+        header.set_entry_type(entry_type);
+        header.set_mode(0o755);
+        header.set_size(data.len() as u64);
+        if let Some(link) = link {
+            header.set_link_name(link).unwrap();
+        }
+        header.set_cksum();
+        tar.append(&header, data).unwrap();
+    }
+
+    #[test]
+    fn unpack_skips_parent_dir_entries() {
+        let root = scratch_dir("parent-dir");
+        let output = root.join("output");
+
+        let mut tar = Builder::new(vec![]);
+        append_entry(&mut tar, "out/", EntryType::Directory, None, b"");
+        append_entry(&mut tar, "out/ok.txt", EntryType::Regular, None, b"ok");
+        append_entry(
+            &mut tar,
+            "out/../evil.txt",
+            EntryType::Regular,
+            None,
+            b"evil",
+        );
+        let archive = tar.into_inner().unwrap();
+
+        unpack_output_archive(&archive, &output).unwrap();
+
+        assert_eq!(fs::read(output.join("ok.txt")).unwrap(), b"ok");
+        assert!(!root.join("evil.txt").exists());
+        assert!(!output.join("evil.txt").exists());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn unpack_rejects_hard_links() {
+        let root = scratch_dir("hard-link");
+        let output = root.join("output");
+
+        let mut tar = Builder::new(vec![]);
+        let target = Path::new("/etc/passwd");
+        append_entry(&mut tar, "out/passwd", EntryType::Link, Some(target), b"");
+        let archive = tar.into_inner().unwrap();
+
+        let err = unpack_output_archive(&archive, &output).unwrap_err();
+        assert!(err.to_string().contains("is a hard link"));
+        assert!(!output.join("passwd").exists());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn unpack_rejects_entries_through_symlinks() {
+        let root = scratch_dir("symlink");
+        let output = root.join("output");
+        let outside = root.join("outside");
+        fs::create_dir_all(&outside).unwrap();
+
+        let mut tar = Builder::new(vec![]);
+        append_entry(&mut tar, "out/dir", EntryType::Symlink, Some(&outside), b"");
+        append_entry(&mut tar, "out/dir/file.txt", EntryType::Regular, None, b"x");
+        let archive = tar.into_inner().unwrap();
+
+        let err = unpack_output_archive(&archive, &output).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("would be written through the symlink"));
+        assert!(!outside.join("file.txt").exists());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn unpack_replaces_symlinks_from_previous_exports() {
+        let root = scratch_dir("replace-symlink");
+        let output = root.join("output");
+        let outside = root.join("outside.txt");
+        fs::write(&outside, b"outside").unwrap();
+        fs::create_dir_all(&output).unwrap();
+        std::os::unix::fs::symlink(&outside, output.join("file.txt")).unwrap();
+
+        let mut tar = Builder::new(vec![]);
+        append_entry(&mut tar, "out/file.txt", EntryType::Regular, None, b"new");
+        let archive = tar.into_inner().unwrap();
+
+        unpack_output_archive(&archive, &output).unwrap();
+
+        assert_eq!(fs::read(&outside).unwrap(), b"outside");
+        assert_eq!(fs::read(output.join("file.txt")).unwrap(), b"new");
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn unpack_single_file() {
+        let root = scratch_dir("single-file");
+        let output = root.join("output");
+
+        let mut tar = Builder::new(vec![]);
+        append_entry(&mut tar, "app", EntryType::Regular, None, b"binary");
+        let archive = tar.into_inner().unwrap();
+
+        unpack_output_archive(&archive, &output).unwrap();
+
+        assert_eq!(fs::read(output.join("app")).unwrap(), b"binary");
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn symlinks_between_output_and_entry() {
+        let root = scratch_dir("ensure-no-symlinks");
+        let output = root.join("output");
+        fs::create_dir_all(output.join("dir")).unwrap();
+        std::os::unix::fs::symlink(&root, output.join("link")).unwrap();
+
+        assert!(ensure_no_symlinks(&output, Path::new("dir/file.txt")).is_ok());
+        assert!(ensure_no_symlinks(&output, Path::new("missing/file.txt")).is_ok());
+        // Symlinks as the entry itself are replaced rather than written through
+        assert!(ensure_no_symlinks(&output, Path::new("link")).is_ok());
+
+        let err = ensure_no_symlinks(&output, Path::new("link/file.txt")).unwrap_err();
+        assert!(err.to_string().contains("symlink"));
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -1,19 +1,30 @@
-use std::{collections::HashMap, path::PathBuf};
+use std::{collections::HashMap, hash::Hasher, path::PathBuf, time::Duration};
 
 use bollard::{
-    container::Config,
-    models::{HostConfig, PortBinding},
+    container::{Config, NetworkingConfig},
+    models::{self, EndpointSettings, HealthConfig, HostConfig, MountTypeEnum, PortBinding},
+};
+use fnv::FnvHasher;
+use ikki_config::{
+    DependencyCondition, Healthcheck, IkkiConfig, Image, KeyValue, Mount, MountType, Network,
+    Secret, Service, Volume, EXCLUSIVE_NETWORKS,
 };
-use ikki_config::{Image, KeyValue, Service};
 
 use crate::docker::DockerError;
 
 #[derive(Debug)]
 pub struct BuildOptions {
     pub path: Option<PathBuf>,
+    pub file: Option<PathBuf>,
+    pub output: Option<PathBuf>,
+    pub output_src: Option<PathBuf>,
     pub pull: Option<String>,
+    pub secret: Option<Secret>,
     pub build_args: HashMap<String, String>,
     pub tag: String,
+    /// Unprefixed tag for images other images depend on, so that `FROM` and `COPY --from`
+    /// in their Dockerfiles keep resolving when tags are prefixed with the project name
+    pub alias: Option<String>,
 }
 
 #[derive(Debug)]
@@ -22,9 +33,108 @@ pub struct RunOptions {
     pub image_name: String,
     pub env: Vec<String>,
     pub ports: Vec<String>,
+    pub mounts: Vec<Mount>,
+    pub user: Option<String>,
+    pub networks: Vec<String>,
+    pub stop_timeout: Option<u32>,
+    pub stop_signal: Option<String>,
+    pub healthcheck: Option<Healthcheck>,
+    /// Project network joined on creation, where the container is reachable by `alias`
+    pub default_network: Option<String>,
+    pub alias: String,
+    pub labels: HashMap<String, String>,
+}
+
+/// Label with the project name, set on every container, network and volume Ikki creates
+pub const PROJECT_LABEL: &str = "com.ikki.project";
+/// Label with the image name a service container runs
+pub const SERVICE_LABEL: &str = "com.ikki.service";
+/// Label with the hash of the configuration a container was created with
+pub const CONFIG_HASH_LABEL: &str = "com.ikki.config-hash";
+
+pub fn project_labels(project: &str) -> HashMap<String, String> {
+    HashMap::from([(PROJECT_LABEL.to_string(), project.to_string())])
 }
 
-pub fn build_options(image: &Image) -> Result<BuildOptions, DockerError> {
+#[derive(Debug)]
+pub struct VolumeOptions {
+    pub name: String,
+    pub driver: Option<String>,
+    pub labels: HashMap<String, String>,
+    pub options: HashMap<String, String>,
+}
+
+#[derive(Debug, Default)]
+pub struct NetworkOptions {
+    pub name: String,
+    pub driver: Option<String>,
+    pub internal: bool,
+    pub labels: HashMap<String, String>,
+    pub options: HashMap<String, String>,
+}
+
+pub fn default_network_name(project: &str) -> String {
+    format!("{}_default", project)
+}
+
+pub fn container_name(project: &str, image: &str) -> String {
+    format!("{}-{}", project, image)
+}
+
+impl RunOptions {
+    /// Attaches the container to the project network, unless it uses an exclusive network mode
+    pub fn with_default_network(mut self, network: String) -> Self {
+        if !self
+            .networks
+            .iter()
+            .any(|network| EXCLUSIVE_NETWORKS.contains(&network.as_str()))
+        {
+            self.default_network = Some(network);
+        }
+        self
+    }
+
+    /// Names and labels the container as the service of its image in `project`
+    pub fn with_project(mut self, project: &str) -> Self {
+        self.container_name = container_name(project, &self.alias);
+        self.labels = project_labels(project);
+        self.labels
+            .insert(SERVICE_LABEL.to_string(), self.alias.clone());
+        self
+    }
+
+    /// Uses the Docker names of the declared volumes and networks of `config`
+    pub fn with_resource_names(mut self, config: &IkkiConfig) -> Self {
+        for network in &mut self.networks {
+            *network = config.network_name(network);
+        }
+        let volume_mounts = self
+            .mounts
+            .iter_mut()
+            .filter(|mount| mount.mount_type == MountType::Volume);
+        for mount in volume_mounts {
+            if let Some(src) = &mut mount.src {
+                *src = config.volume_name(&src.to_string_lossy()).into();
+            }
+        }
+        self
+    }
+
+    /// Network joined when the container is created
+    pub fn network_mode(&self) -> Option<&String> {
+        self.default_network
+            .as_ref()
+            .or_else(|| self.networks.first())
+    }
+
+    /// Networks connected after the container is created
+    pub fn connected_networks(&self) -> impl Iterator<Item = &String> {
+        let skip = usize::from(self.default_network.is_none());
+        self.networks.iter().skip(skip)
+    }
+}
+
+pub fn build_options(config: &IkkiConfig, image: &Image) -> Result<BuildOptions, DockerError> {
     let build_args = image
         .build_args
         .iter()
@@ -37,14 +147,55 @@ pub fn build_options(image: &Image) -> Result<BuildOptions, DockerError> {
         ));
     }
 
+    let tag = config.image_tag(&image.name);
+    let has_dependers = !config.dependency_graph().successors(&image.name).is_empty();
+    let alias = (tag != image.name && has_dependers).then(|| image.name.clone());
+
     Ok(BuildOptions {
         build_args,
         pull: image.pull.clone(),
+        secret: image.secret.clone(),
         path: image.path.clone(),
-        tag: image.name.clone(),
+        file: image.file.clone(),
+        output: image.output.clone(),
+        output_src: image.output_src.clone(),
+        tag,
+        alias,
     })
 }
 
+fn key_values(pairs: &[KeyValue]) -> HashMap<String, String> {
+    pairs
+        .iter()
+        .map(|KeyValue(k, v)| (k.to_string(), v.to_string()))
+        .collect()
+}
+
+pub fn volume_options(volume: &Volume, config: &IkkiConfig) -> VolumeOptions {
+    let mut labels = key_values(&volume.labels);
+    labels.extend(project_labels(&config.project_name()));
+
+    VolumeOptions {
+        name: config.volume_name(&volume.name),
+        driver: volume.driver.clone(),
+        labels,
+        options: key_values(&volume.options),
+    }
+}
+
+pub fn network_options(network: &Network, config: &IkkiConfig) -> NetworkOptions {
+    let mut labels = key_values(&network.labels);
+    labels.extend(project_labels(&config.project_name()));
+
+    NetworkOptions {
+        name: config.network_name(&network.name),
+        driver: network.driver.clone(),
+        internal: network.internal,
+        labels,
+        options: key_values(&network.options),
+    }
+}
+
 type ContainerPortConfig = String;
 
 fn parse_port_binding(binding: String) -> (ContainerPortConfig, PortBinding) {
@@ -80,32 +231,141 @@ fn create_env_config(env: Vec<KeyValue>) -> Vec<String> {
         .collect()
 }
 
+fn create_mounts_config(mounts: Vec<Mount>) -> Vec<models::Mount> {
+    mounts
+        .into_iter()
+        .map(|mount| models::Mount {
+            typ: Some(match mount.mount_type {
+                MountType::Bind => MountTypeEnum::BIND,
+                MountType::Volume => MountTypeEnum::VOLUME,
+                MountType::Tmpfs => MountTypeEnum::TMPFS,
+            }),
+            source: mount.src.map(|src| src.display().to_string()),
+            target: Some(mount.dest.display().to_string()),
+            read_only: Some(mount.readonly),
+            ..Default::default()
+        })
+        .collect()
+}
+
 pub fn create_run_options(
     (container_name, image_name, service): (String, String, Service),
 ) -> RunOptions {
     RunOptions {
-        container_name: container_name,
         env: create_env_config(service.env),
         ports: service.ports.unwrap_or_default(),
-        image_name: image_name,
+        mounts: service.mounts,
+        user: service.user,
+        networks: service.networks.unwrap_or_default(),
+        stop_timeout: service.stop_timeout,
+        stop_signal: service.stop_signal,
+        healthcheck: service.healthcheck,
+        default_network: None,
+        alias: container_name.clone(),
+        labels: HashMap::new(),
+        container_name,
+        image_name,
     }
 }
 
-pub fn create_container_config(
-    container_name: &str,
-    image_name: &str,
-    service: Service,
-) -> Config<String> {
+fn seconds_to_nanos(seconds: u32) -> i64 {
+    i64::from(seconds) * 1_000_000_000
+}
+
+/// A single test argument runs in the container's shell, several are executed directly.
+fn create_healthcheck_config(healthcheck: Healthcheck) -> HealthConfig {
+    let kind = if healthcheck.test.len() == 1 {
+        "CMD-SHELL"
+    } else {
+        "CMD"
+    };
+    let mut test = vec![kind.to_string()];
+    test.extend(healthcheck.test);
+
+    HealthConfig {
+        test: Some(test),
+        interval: healthcheck.interval.map(seconds_to_nanos),
+        timeout: healthcheck.timeout.map(seconds_to_nanos),
+        retries: healthcheck.retries.map(i64::from),
+        start_period: healthcheck.start_period.map(seconds_to_nanos),
+    }
+}
+
+/// Health check defaults of Docker, in seconds
+const DEFAULT_HEALTH_INTERVAL: u32 = 30;
+const DEFAULT_HEALTH_TIMEOUT: u32 = 30;
+const DEFAULT_HEALTH_RETRIES: u32 = 3;
+
+/// How long a service with a `completed-successfully` dependent may run
+const COMPLETION_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// How long a `condition` on a service with `healthcheck` may take to be met. Docker marks a
+/// container unhealthy within its start period plus `retries` failed checks, plus one to spare.
+pub fn condition_timeout(
+    condition: DependencyCondition,
+    healthcheck: Option<&Healthcheck>,
+) -> Duration {
+    match condition {
+        DependencyCondition::Started => Duration::ZERO,
+        DependencyCondition::CompletedSuccessfully => COMPLETION_TIMEOUT,
+        DependencyCondition::Healthy => {
+            let start_period = healthcheck.and_then(|hc| hc.start_period).unwrap_or(0);
+            let interval = healthcheck
+                .and_then(|hc| hc.interval)
+                .unwrap_or(DEFAULT_HEALTH_INTERVAL);
+            let timeout = healthcheck
+                .and_then(|hc| hc.timeout)
+                .unwrap_or(DEFAULT_HEALTH_TIMEOUT);
+            let retries = healthcheck
+                .and_then(|hc| hc.retries)
+                .unwrap_or(DEFAULT_HEALTH_RETRIES);
+
+            let seconds =
+                u64::from(start_period) + u64::from(interval + timeout) * (u64::from(retries) + 1);
+            Duration::from_secs(seconds)
+        }
+    }
+}
+
+/// Stable hash of everything a container is created with, including networks connected
+/// after creation. Maps are serialized with sorted keys, so their order does not matter.
+pub fn config_hash(config: &Config<String>, connected_networks: &[String]) -> String {
+    let mut hasher = FnvHasher::default();
+    let value = serde_json::json!({
+        "config": config,
+        "networks": connected_networks,
+    });
+    hasher.write(value.to_string().as_bytes());
+    format!("{:016x}", hasher.finish())
+}
+
+pub fn create_container_config(options: RunOptions) -> Config<String> {
     let mut config = Config::default();
+    let network_mode = options.network_mode().cloned();
 
-    let options = create_run_options((container_name.to_string(), image_name.to_string(), service));
+    if let Some(network) = &options.default_network {
+        let endpoint = EndpointSettings {
+            aliases: Some(vec![options.alias.clone()]),
+            ..Default::default()
+        };
+        config.networking_config = Some(NetworkingConfig {
+            endpoints_config: HashMap::from([(network.clone(), endpoint)]),
+        });
+    }
 
     config.image = Some(options.image_name);
     config.host_config = Some(HostConfig {
         port_bindings: Some(create_ports_config(options.ports)),
+        mounts: Some(create_mounts_config(options.mounts)),
+        network_mode,
         ..Default::default()
     });
     config.env = Some(options.env);
+    config.user = options.user;
+    config.stop_timeout = options.stop_timeout.map(i64::from);
+    config.stop_signal = options.stop_signal;
+    config.healthcheck = options.healthcheck.map(create_healthcheck_config);
+    config.labels = Some(options.labels);
 
     config
 }
@@ -1,4 +1,48 @@
-use crate::docker_config::{BuildOptions, RunOptions};
+use std::collections::HashMap;
+
+use ikki_config::DependencyCondition;
+
+use crate::docker_config::{BuildOptions, NetworkOptions, RunOptions, VolumeOptions};
+
+/// `flag` for every pair, sorted by name so that the output is stable
+fn explain_pairs(flag: &str, pairs: &HashMap<String, String>) -> String {
+    let mut pairs: Vec<_> = pairs.iter().collect();
+    pairs.sort();
+
+    pairs
+        .into_iter()
+        .map(|(name, value)| format!(" {} {}={}", flag, name, value))
+        .collect()
+}
+
+fn explain_labels(labels: &HashMap<String, String>) -> String {
+    explain_pairs("--label", labels)
+}
+
+/// Quotes `arg` for a POSIX shell, unless it only has characters the shell leaves alone
+fn shell_quote(arg: &str) -> String {
+    let is_plain = |c: char| c.is_ascii_alphanumeric() || "-_./:=@%+,".contains(c);
+    if !arg.is_empty() && arg.chars().all(is_plain) {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+/// Shell command that blocks until the container meets `condition`, if there is anything to wait for
+pub fn explain_wait(container_name: &str, condition: DependencyCondition) -> Option<String> {
+    match condition {
+        DependencyCondition::Started => None,
+        DependencyCondition::Healthy => Some(format!(
+            "until [ \"$(docker inspect --format '{{{{.State.Health.Status}}}}' {})\" = healthy ]; do sleep 1; done",
+            container_name
+        )),
+        DependencyCondition::CompletedSuccessfully => Some(format!(
+            "[ \"$(docker wait {})\" = 0 ]",
+            container_name
+        )),
+    }
+}
 
 impl BuildOptions {
     pub fn explain(&self) -> String {
@@ -10,12 +54,30 @@ impl BuildOptions {
             return s;
         }
 
+        // secrets are only available to BuildKit builds
+        if self.secret.is_some() {
+            s.push_str("DOCKER_BUILDKIT=1 ");
+        }
+
         s.push_str("docker build ");
 
+        // file
+        if let Some(file) = &self.file {
+            let file = format!("-f {} ", file.display());
+            s.push_str(&file);
+        }
+
         // build-args
-        for (name, value) in &self.build_args {
-            let arg = format!("--build-arg {}={} ", name, value);
-            s.push_str(&arg);
+        let build_args = explain_pairs("--build-arg", &self.build_args);
+        s.push_str(build_args.trim_start());
+        if !build_args.is_empty() {
+            s.push(' ');
+        }
+
+        // secret
+        if let Some(secret) = &self.secret {
+            let secret = format!("--secret id={},src={} ", secret.id, secret.src.display());
+            s.push_str(&secret);
         }
 
         // tag
@@ -25,6 +87,35 @@ impl BuildOptions {
         // path
         s.push_str(&self.path.as_ref().unwrap().display().to_string());
 
+        // unprefixed tag for dependers
+        if let Some(alias) = &self.alias {
+            s.push_str(&format!("\ndocker tag {} {}", self.tag, alias));
+        }
+
+        // output
+        if let Some(output) = &self.output {
+            let container = format!("ikki-output-{}", self.tag);
+            let src = match &self.output_src {
+                Some(src) => src.display().to_string(),
+                None => format!(
+                    "$(docker image inspect --format '{{{{.Config.WorkingDir}}}}' {})",
+                    self.tag
+                ),
+            };
+            s.push_str(&format!(
+                "\ndocker create --name {} {}",
+                container, self.tag
+            ));
+            s.push_str(&format!("\nmkdir -p {}", output.display()));
+            s.push_str(&format!(
+                "\ndocker cp {}:\"{}/.\" {}",
+                container,
+                src.trim_end_matches('/'),
+                output.display()
+            ));
+            s.push_str(&format!("\ndocker rm {}", container));
+        }
+
         s
     }
 }
@@ -51,9 +142,143 @@ impl RunOptions {
             s.push_str(&publish);
         }
 
+        // mounts
+        for mount in &self.mounts {
+            let mut spec = format!(" --mount type={}", mount.mount_type);
+            if let Some(src) = &mount.src {
+                spec.push_str(&format!(",src={}", src.display()));
+            }
+            spec.push_str(&format!(",dst={}", mount.dest.display()));
+            if mount.readonly {
+                spec.push_str(",readonly");
+            }
+            s.push_str(&spec);
+        }
+
+        // user
+        if let Some(user) = &self.user {
+            let user = format!(" --user {}", user);
+            s.push_str(&user);
+        }
+
+        // stop settings
+        if let Some(timeout) = self.stop_timeout {
+            let timeout = format!(" --stop-timeout {}", timeout);
+            s.push_str(&timeout);
+        }
+        if let Some(signal) = &self.stop_signal {
+            let signal = format!(" --stop-signal {}", signal);
+            s.push_str(&signal);
+        }
+
+        // restart policies are applied by `up --watch` rather than Docker, so there is no
+        // `--restart` flag, like for the containers `up` runs
+
+        // healthcheck
+        if let Some(healthcheck) = &self.healthcheck {
+            // `--health-cmd` always runs in the container's shell, so arguments that are
+            // executed directly are quoted to reach the command unchanged
+            let cmd = match healthcheck.test.as_slice() {
+                [shell_cmd] => format!(" --health-cmd {}", shell_quote(shell_cmd)),
+                args => {
+                    let args: Vec<String> = args.iter().map(|arg| shell_quote(arg)).collect();
+                    format!(" --health-cmd {}", shell_quote(&args.join(" ")))
+                }
+            };
+            s.push_str(&cmd);
+            if let Some(interval) = healthcheck.interval {
+                s.push_str(&format!(" --health-interval {}s", interval));
+            }
+            if let Some(timeout) = healthcheck.timeout {
+                s.push_str(&format!(" --health-timeout {}s", timeout));
+            }
+            if let Some(retries) = healthcheck.retries {
+                s.push_str(&format!(" --health-retries {}", retries));
+            }
+            if let Some(start_period) = healthcheck.start_period {
+                s.push_str(&format!(" --health-start-period {}s", start_period));
+            }
+        }
+
+        // labels
+        s.push_str(&explain_labels(&self.labels));
+
+        // networks, one at creation and the rest connected afterwards
+        if let Some(network) = self.network_mode() {
+            let network = format!(" --network {}", network);
+            s.push_str(&network);
+        }
+        if self.default_network.is_some() {
+            let alias = format!(" --network-alias {}", self.alias);
+            s.push_str(&alias);
+        }
+
         s.push(' ');
         s.push_str(&self.image_name);
 
+        for network in self.connected_networks() {
+            let connect = format!(
+                "\ndocker network connect {} {}",
+                network, self.container_name
+            );
+            s.push_str(&connect);
+        }
+
+        s
+    }
+}
+
+impl VolumeOptions {
+    pub fn explain(&self) -> String {
+        let mut s = String::new();
+
+        s.push_str("docker volume create");
+
+        // driver
+        if let Some(driver) = &self.driver {
+            let driver = format!(" --driver {}", driver);
+            s.push_str(&driver);
+        }
+
+        // labels
+        s.push_str(&explain_labels(&self.labels));
+
+        // driver options
+        s.push_str(&explain_pairs("--opt", &self.options));
+
+        s.push(' ');
+        s.push_str(&self.name);
+
+        s
+    }
+}
+
+impl NetworkOptions {
+    pub fn explain(&self) -> String {
+        let mut s = String::new();
+
+        s.push_str("docker network create");
+
+        // driver
+        if let Some(driver) = &self.driver {
+            let driver = format!(" --driver {}", driver);
+            s.push_str(&driver);
+        }
+
+        // internal
+        if self.internal {
+            s.push_str(" --internal");
+        }
+
+        // labels
+        s.push_str(&explain_labels(&self.labels));
+
+        // driver options
+        s.push_str(&explain_pairs("--opt", &self.options));
+
+        s.push(' ');
+        s.push_str(&self.name);
+
         s
     }
 }
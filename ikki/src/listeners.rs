@@ -1,6 +1,10 @@
+use std::collections::HashMap;
 use std::time::Duration;
 
-use futures::prelude::*;
+use bollard::models::EventMessage;
+use bollard::system::EventsOptions;
+use bollard::Docker;
+use futures::StreamExt;
 use notify::DebouncedEvent;
 use notify::Watcher;
 use notify::{watcher, RecursiveMode};
@@ -14,6 +18,7 @@ use tracing::debug;
 use crate::supervisor::Event;
 use crate::supervisor::EventSender;
 
+use crate::docker_config::{PROJECT_LABEL, SERVICE_LABEL};
 use crate::supervisor::ImageSourceLocations;
 use crate::IkkiError;
 
@@ -116,3 +121,128 @@ fn watch_file_changes(
 
     Ok(())
 }
+
+struct DockerEventListener {
+    shutdown: oneshot::Receiver<()>,
+    event_sender: EventSender,
+    docker: Docker,
+    project: String,
+}
+
+impl DockerEventListener {
+    fn new(
+        event_sender: EventSender,
+        shutdown: oneshot::Receiver<()>,
+        docker: Docker,
+        project: String,
+    ) -> Self {
+        Self {
+            event_sender,
+            shutdown,
+            docker,
+            project,
+        }
+    }
+}
+
+pub struct DockerEventListenerHandle {
+    sender: oneshot::Sender<()>,
+    handle: JoinHandle<Result<(), IkkiError>>,
+}
+
+impl DockerEventListenerHandle {
+    pub fn new(docker: Docker, project: String, event_sender: EventSender) -> Self {
+        debug!("setup Docker event listener");
+        let (sender, rx) = oneshot::channel();
+        let listener = DockerEventListener::new(event_sender, rx, docker, project);
+        let handle = task::spawn(async move {
+            let result = run_docker_event_listener(listener).await;
+            if let Err(e) = &result {
+                println!("Ikki error: {}, exited services are no longer restarted", e);
+            }
+            result
+        });
+        debug!("Docker event listener setup successful");
+        Self { sender, handle }
+    }
+
+    pub async fn shutdown(self) {
+        let _ = self.sender.send(());
+        let _ = self.handle.await;
+    }
+}
+
+async fn run_docker_event_listener(mut listener: DockerEventListener) -> Result<(), IkkiError> {
+    debug!("subscribing to Docker events");
+
+    // `health_status` also matches the `health_status: <status>` actions
+    let filters = HashMap::from([
+        ("type".to_string(), vec!["container".to_string()]),
+        (
+            "event".to_string(),
+            vec![
+                "kill".to_string(),
+                "die".to_string(),
+                "start".to_string(),
+                "health_status".to_string(),
+            ],
+        ),
+        (
+            "label".to_string(),
+            vec![format!("{}={}", PROJECT_LABEL, listener.project)],
+        ),
+    ]);
+    let options = EventsOptions {
+        filters,
+        ..Default::default()
+    };
+    let mut events = listener.docker.events(Some(options));
+
+    loop {
+        tokio::select! {
+            _ = &mut listener.shutdown => {
+                debug!("Docker event listener successfully shutdown");
+                return Ok(())
+            },
+            message = events.next() => {
+                let message = message
+                    .ok_or_else(|| IkkiError::DockerEvents("event stream ended".to_string()))?
+                    .map_err(|e| IkkiError::DockerEvents(e.to_string()))?;
+                debug!(?message, "received Docker event");
+                if let Some(event) = container_event(message) {
+                    listener.event_sender.send(event).await
+                        .map_err(|e| IkkiError::DockerEvents(e.to_string()))?
+                }
+            }
+        }
+    }
+}
+
+/// Signals usually sent with `docker kill -s` to make a process reload rather than stop:
+/// SIGHUP, SIGUSR1, SIGUSR2 and SIGWINCH
+const RELOAD_SIGNALS: [&str; 4] = ["1", "10", "12", "28"];
+
+/// Supervisor event for a Docker event of a service container, if it is one the supervisor handles
+fn container_event(message: EventMessage) -> Option<Event> {
+    let actor = message.actor?;
+    let attributes = actor.attributes.unwrap_or_default();
+    let service = attributes.get(SERVICE_LABEL)?.clone();
+    let reload = attributes
+        .get("signal")
+        .is_some_and(|signal| RELOAD_SIGNALS.contains(&signal.as_str()));
+
+    match message.action?.as_str() {
+        "kill" if !reload => Some(Event::ContainerKilled(actor.id?)),
+        "die" => Some(Event::ContainerDied {
+            service,
+            id: actor.id?,
+            exit_code: attributes
+                .get("exitCode")
+                .and_then(|code| code.parse().ok())
+                .unwrap_or_default(),
+        }),
+        "start" => Some(Event::ContainerStarted(actor.id?)),
+        "health_status: unhealthy" => Some(Event::ContainerUnhealthy(service)),
+        _ => None,
+    }
+}
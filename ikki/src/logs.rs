@@ -0,0 +1,472 @@
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bollard::container::LogsOptions;
+use bollard::Docker;
+use crossterm::style::{Color, Stylize};
+use crossterm::tty::IsTty;
+use futures::StreamExt;
+use tokio::task;
+use tokio::task::JoinHandle;
+use tracing::debug;
+
+use crate::docker;
+use crate::docker::DockerError;
+
+type ImageName = String;
+
+/// Colors of service prefixes, assigned in order and reused once exhausted
+const COLORS: [Color; 6] = [
+    Color::Cyan,
+    Color::Yellow,
+    Color::Green,
+    Color::Magenta,
+    Color::Blue,
+    Color::Red,
+];
+
+/// How often a followed service is checked for a running container when it has none
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+/// Longest delay between attempts to stream logs that keep failing
+const MAX_RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Which part of the service logs to show
+#[derive(Debug, Clone, Default)]
+pub struct LogOptions {
+    /// Keep streaming, also from containers that are restarted or recreated
+    pub follow: bool,
+    /// Number of lines to show from the end of the logs, all if `None`
+    pub tail: Option<usize>,
+    /// Unix timestamp of the oldest line to show
+    pub since: i64,
+    pub timestamps: bool,
+    /// Prefix lines with the service name
+    pub prefix: bool,
+}
+
+/// Prefixes every line with the colored name of its service, padded so that lines align.
+#[derive(Debug, Clone)]
+pub struct LogFormatter {
+    prefixes: HashMap<ImageName, String>,
+}
+
+impl LogFormatter {
+    pub fn new(services: &[ImageName], prefix: bool) -> Self {
+        let width = services.iter().map(String::len).max().unwrap_or_default();
+        let colored = std::io::stdout().is_tty();
+
+        let prefixes = services
+            .iter()
+            .zip(COLORS.iter().cycle())
+            .map(|(service, color)| {
+                let prefix = match (prefix, colored) {
+                    (false, _) => String::new(),
+                    (true, false) => format!("{:<width$} | ", service, width = width),
+                    (true, true) => format!(
+                        "{} ",
+                        format!("{:<width$} |", service, width = width).with(*color)
+                    ),
+                };
+                (service.clone(), prefix)
+            })
+            .collect();
+
+        Self { prefixes }
+    }
+
+    pub fn print(&self, service: &str, line: &str) {
+        let prefix = self
+            .prefixes
+            .get(service)
+            .map(String::as_str)
+            .unwrap_or_default();
+
+        println!("{}{}", prefix, line);
+    }
+}
+
+/// Time Docker received a log line, ordered so that streams can resume after the last line shown
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct LogTimestamp {
+    seconds: i64,
+    nanos: u32,
+}
+
+impl LogTimestamp {
+    /// Parses the RFC 3339 UTC timestamp Docker prefixes lines with, such as
+    /// `2022-07-14T09:21:03.123456789Z`.
+    fn parse(value: &str) -> Option<Self> {
+        let (date, time) = value.strip_suffix('Z')?.split_once('T')?;
+        let mut date = date.splitn(3, '-').map(str::parse::<i64>);
+        let (year, month, day) = (date.next()?.ok()?, date.next()?.ok()?, date.next()?.ok()?);
+
+        let (time, fraction) = time.split_once('.').unwrap_or((time, ""));
+        let mut time = time.splitn(3, ':').map(str::parse::<i64>);
+        let (hour, minute, second) = (time.next()?.ok()?, time.next()?.ok()?, time.next()?.ok()?);
+
+        let nanos = if fraction.is_empty() {
+            0
+        } else {
+            format!("{:0<9}", fraction).get(..9)?.parse().ok()?
+        };
+
+        let seconds = days_from_civil(year, month, day) * 24 * 60 * 60
+            + hour * 60 * 60
+            + minute * 60
+            + second;
+
+        Some(Self { seconds, nanos })
+    }
+}
+
+/// Days between the Unix epoch and a date of the proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Current Unix timestamp, to pass as `since` to show lines written from now on
+pub fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+/// Parses `--since`, either a Unix timestamp or a duration before now such as `10m`,
+/// with an `s`, `m`, `h` or `d` suffix.
+pub fn parse_since(value: &str) -> Result<i64, String> {
+    if let Ok(timestamp) = value.parse() {
+        return Ok(timestamp);
+    }
+
+    let unit = match value.chars().last() {
+        Some('s') => 1,
+        Some('m') => 60,
+        Some('h') => 60 * 60,
+        Some('d') => 24 * 60 * 60,
+        _ => {
+            return Err(format!(
+                "invalid time `{}`, expected a Unix timestamp or a duration such as `10m`",
+                value
+            ))
+        }
+    };
+    let amount: i64 = value[..value.len() - 1]
+        .parse()
+        .map_err(|_| format!("invalid duration `{}`", value))?;
+
+    Ok(now() - amount * unit)
+}
+
+/// Prints the logs of a container until they end, printing nothing if there is no such container.
+/// Logs are requested with timestamps, which are used to skip lines up to `last` and to track
+/// the last line shown, and stripped unless `timestamps` is set.
+async fn print_logs(
+    docker: &Docker,
+    container: &str,
+    service: &str,
+    options: LogsOptions<String>,
+    timestamps: bool,
+    last: &mut Option<LogTimestamp>,
+    formatter: &LogFormatter,
+) -> Result<(), DockerError> {
+    let mut logs = docker.logs(container, Some(options));
+
+    while let Some(output) = logs.next().await {
+        let output = match output {
+            Ok(output) => output,
+            Err(e) if docker::is_not_found(&e) => break,
+            Err(e) => return Err(e.into()),
+        };
+
+        for line in output.to_string().lines() {
+            let (timestamp, text) = line.split_once(' ').unwrap_or((line, ""));
+            let timestamp = match LogTimestamp::parse(timestamp) {
+                Some(timestamp) => timestamp,
+                None => {
+                    formatter.print(service, line);
+                    continue;
+                }
+            };
+            if last.is_some_and(|last| timestamp <= last) {
+                continue;
+            }
+            *last = Some(timestamp);
+
+            formatter.print(service, if timestamps { line } else { text });
+        }
+    }
+
+    Ok(())
+}
+
+async fn stream_service_logs(
+    docker: Docker,
+    project: String,
+    service: ImageName,
+    options: LogOptions,
+    formatter: LogFormatter,
+) {
+    let mut logs_options = LogsOptions {
+        follow: options.follow,
+        stdout: true,
+        stderr: true,
+        since: options.since,
+        timestamps: true,
+        tail: options
+            .tail
+            .map_or_else(|| "all".to_string(), |tail| tail.to_string()),
+        ..Default::default()
+    };
+    let mut last = None;
+
+    if !options.follow {
+        let result = match docker::service_container(&docker, &project, &service, false).await {
+            Ok(Some(id)) => {
+                print_logs(
+                    &docker,
+                    &id,
+                    &service,
+                    logs_options,
+                    options.timestamps,
+                    &mut last,
+                    &formatter,
+                )
+                .await
+            }
+            Ok(None) => {
+                debug!("service {} has no container", service);
+                Ok(())
+            }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            println!("Ikki error: {}", e);
+        }
+        return;
+    }
+
+    // The current container is shown whether it runs or not, later ones once they run
+    let mut running = false;
+    let mut retry_interval = POLL_INTERVAL;
+
+    loop {
+        let result = match docker::service_container(&docker, &project, &service, running).await {
+            Ok(Some(id)) => {
+                debug!("streaming logs of service {} ({})", service, id);
+                running = true;
+                let result = print_logs(
+                    &docker,
+                    &id,
+                    &service,
+                    logs_options.clone(),
+                    options.timestamps,
+                    &mut last,
+                    &formatter,
+                )
+                .await;
+
+                // Later streams resume after the last line shown, the timestamps of lines
+                // written in the same second are compared to skip the ones already shown
+                if let Some(last) = last {
+                    logs_options.since = last.seconds;
+                }
+                logs_options.tail = "all".to_string();
+                result
+            }
+            Ok(None) => Ok(()),
+            Err(e) => Err(e),
+        };
+
+        match result {
+            Ok(()) => {
+                retry_interval = POLL_INTERVAL;
+                tokio::time::sleep(POLL_INTERVAL).await;
+            }
+            Err(e) => {
+                println!("Ikki error: {}", e);
+                tokio::time::sleep(retry_interval).await;
+                retry_interval = (retry_interval * 2).min(MAX_RETRY_INTERVAL);
+            }
+        }
+    }
+}
+
+/// Streams the logs of the containers of `services` concurrently, interleaving their lines.
+pub struct LogsHandle {
+    handles: Vec<JoinHandle<()>>,
+}
+
+impl LogsHandle {
+    pub fn new(
+        docker: Docker,
+        project: String,
+        services: Vec<ImageName>,
+        options: LogOptions,
+    ) -> Self {
+        debug!("setup log streams");
+        let formatter = LogFormatter::new(&services, options.prefix);
+        let handles = services
+            .into_iter()
+            .map(|service| {
+                task::spawn(stream_service_logs(
+                    docker.clone(),
+                    project.clone(),
+                    service,
+                    options.clone(),
+                    formatter.clone(),
+                ))
+            })
+            .collect();
+
+        Self { handles }
+    }
+
+    /// Waits until every stream ends, which only happens without `follow`.
+    pub async fn wait(self) {
+        for handle in self.handles {
+            let _ = handle.await;
+        }
+    }
+
+    pub async fn shutdown(self) {
+        debug!("shutting down log streams...");
+        for handle in self.handles {
+            handle.abort();
+            let _ = handle.await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_timestamp_with_nanos() {
+        assert_eq!(
+            LogTimestamp::parse("2022-07-14T09:21:03.123456789Z"),
+            Some(LogTimestamp {
+                seconds: 1_657_790_463,
+                nanos: 123_456_789
+            })
+        );
+    }
+
+    #[test]
+    fn parse_timestamp_with_short_fraction() {
+        assert_eq!(
+            LogTimestamp::parse("2022-07-14T09:21:03.1234Z"),
+            Some(LogTimestamp {
+                seconds: 1_657_790_463,
+                nanos: 123_400_000
+            })
+        );
+    }
+
+    #[test]
+    fn parse_timestamp_without_nanos() {
+        assert_eq!(
+            LogTimestamp::parse("2022-07-14T09:21:03Z"),
+            Some(LogTimestamp {
+                seconds: 1_657_790_463,
+                nanos: 0
+            })
+        );
+    }
+
+    #[test]
+    fn parse_timestamp_on_leap_day() {
+        assert_eq!(
+            LogTimestamp::parse("2024-02-29T00:00:00Z"),
+            Some(LogTimestamp {
+                seconds: 1_709_164_800,
+                nanos: 0
+            })
+        );
+        assert_eq!(
+            LogTimestamp::parse("2024-03-01T00:00:00Z"),
+            Some(LogTimestamp {
+                seconds: 1_709_164_800 + 24 * 60 * 60,
+                nanos: 0
+            })
+        );
+    }
+
+    #[test]
+    fn parse_timestamp_at_epoch() {
+        assert_eq!(
+            LogTimestamp::parse("1970-01-01T00:00:00Z"),
+            Some(LogTimestamp {
+                seconds: 0,
+                nanos: 0
+            })
+        );
+    }
+
+    #[test]
+    fn parse_invalid_timestamp() {
+        for value in [
+            "hello",
+            "2022-07-14T09:21:03",
+            "2022-07-14 09:21:03Z",
+            "2022-07T09:21:03Z",
+            "2022-07-14T09:21Z",
+            "2022-07-14T09:21:03.12a4Z",
+        ] {
+            assert_eq!(LogTimestamp::parse(value), None, "{}", value);
+        }
+    }
+
+    #[test]
+    fn days_from_civil_dates() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(1969, 12, 31), -1);
+        assert_eq!(days_from_civil(2000, 3, 1), 11_017);
+        assert_eq!(days_from_civil(2024, 2, 29), 19_782);
+    }
+
+    #[test]
+    fn parse_since_timestamp() {
+        assert_eq!(parse_since("1657790463"), Ok(1_657_790_463));
+    }
+
+    #[test]
+    fn parse_since_durations() {
+        for (value, secs) in [
+            ("30s", 30),
+            ("10m", 10 * 60),
+            ("2h", 2 * 60 * 60),
+            ("1d", 86_400),
+        ] {
+            let before = now();
+            let since = parse_since(value).unwrap();
+            let after = now();
+            assert!(
+                (before - secs..=after - secs).contains(&since),
+                "{} gave {}",
+                value,
+                since
+            );
+        }
+    }
+
+    #[test]
+    fn parse_since_invalid() {
+        match parse_since("yesterday") {
+            Err(err) => assert!(err.contains("expected a Unix timestamp"), "{}", err),
+            other => panic!("expected an error, got {:?}", other),
+        }
+        match parse_since("tenm") {
+            Err(err) => assert!(err.contains("invalid duration `tenm`"), "{}", err),
+            other => panic!("expected an error, got {:?}", other),
+        }
+        assert!(parse_since("").is_err());
+    }
+}
@@ -1,14 +1,15 @@
 use crate::args::*;
 
+use builder::BuildReport;
 use clap::Parser;
 use docker::DockerError;
 use miette::{self, Diagnostic};
-use std::path::Path;
+use std::{path::Path, process};
 
 use ikki_config::*;
 use thiserror::Error;
 use tokio::fs;
-use tracing::{debug, error};
+use tracing::debug;
 use tracing_subscriber::EnvFilter;
 
 mod args;
@@ -19,22 +20,50 @@ mod docker;
 mod docker_config;
 mod explain;
 mod listeners;
+mod logs;
 mod supervisor;
 
 type Result<T> = miette::Result<T>;
 
+/// Exit code for `--keep-going` builds where some images failed
+const BUILD_FAILURES_EXIT_CODE: i32 = 2;
+
 #[derive(Debug, Error, Diagnostic)]
 pub enum IkkiError {
     #[error("Image does not exist: {0}")]
     NoSuchImage(String),
+    #[error("Service does not exist: {0}")]
+    NoSuchService(String),
+    #[error("Cannot watch source path `{}` of image `{image}`", path.display())]
+    SourcePath {
+        image: String,
+        path: std::path::PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
     #[error("FS change watcher failed")]
     FileWatcher,
+    #[error("Docker event listener failed: {0}")]
+    DockerEvents(String),
     #[error("No Ikki configuration file found at: {0}")]
     NoConfig(String),
     #[error("Ikki configuration error")]
+    #[diagnostic(transparent)]
     Config(#[from] ikki_config::IkkiConfigError),
     #[error("Docker build failed")]
     Build(#[from] DockerError),
+    #[error("Failed to build image `{0}`")]
+    ImageBuild(String, #[source] DockerError),
+    #[error(
+        "{} image(s) failed to build and {} were skipped",
+        .0.failed.len(),
+        .0.skipped.len()
+    )]
+    BuildFailures(BuildReport),
+    #[error("{} container(s) could not be stopped and removed", .0.len())]
+    StopFailures(Vec<(String, DockerError)>),
+    #[error("{} image(s) could not be removed", .0.len())]
+    RemoveImageFailures(Vec<(String, DockerError)>),
     #[error("Unexpected error: {0}")]
     Other(String),
 }
@@ -68,15 +97,29 @@ async fn main() -> Result<()> {
     debug!("initialized tracing_subscriber");
 
     let args = Ikki::parse();
-    let config = read_config(args.file.clone()).await?;
+    let mut config = read_config(args.file.clone()).await?;
+
+    if let Some(name) = args.project_name {
+        config.set_project_name(name);
+    }
 
     debug!("loaded configuration from {}", args.file.display());
 
-    match args.command {
-        Command::Up(_opts) => cmd::up(config).await?,
-        Command::Explain => cmd::explain(config).await?,
-        _ => unimplemented!(),
+    let result = match args.command {
+        Command::Build(opts) => cmd::build(config, opts).await,
+        Command::Up(opts) => cmd::up(config, opts).await,
+        Command::Down(opts) => cmd::down(config, opts).await,
+        Command::Logs(opts) => cmd::logs(config, opts).await,
+        Command::Explain => cmd::explain(config).await,
+    };
+
+    // Let scripts tell builds that partially failed apart from other errors
+    if let Err(report) = &result {
+        if let Some(IkkiError::BuildFailures(_)) = report.downcast_ref::<IkkiError>() {
+            eprintln!("Error: {:?}", report);
+            process::exit(BUILD_FAILURES_EXIT_CODE);
+        }
     }
 
-    Ok(())
+    result
 }
@@ -1,55 +1,197 @@
 use std::collections::HashMap;
 use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use bollard::Docker;
+use ikki_config::RestartPolicy;
 use tokio::sync::mpsc;
 use tokio::task;
 use tokio::task::JoinHandle;
 use tracing::debug;
 
 use crate::builder::BuilderHandle;
-use crate::listeners::FsEventListenerHandle;
+use crate::console;
+use crate::docker;
+use crate::listeners::{DockerEventListenerHandle, FsEventListenerHandle};
 use crate::IkkiError;
 
 type ImageName = String;
 pub type ImageSourceLocations = HashMap<PathBuf, ImageName>;
+pub type RestartPolicies = HashMap<ImageName, RestartPolicy>;
+pub type StopTimeouts = HashMap<ImageName, Duration>;
+
+/// Delay before the first restart of a service, doubled for every consecutive restart
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// Time a restarted container has to run for its next exit to start a new series of restarts
+const RESET_BACKOFF_AFTER: Duration = Duration::from_secs(60);
+/// Consecutive restarts after which a service is reported as crash looping
+const CRASH_LOOP_RESTARTS: u32 = 5;
+/// Time Docker waits for a container to stop before killing it, unless the service sets one
+const DEFAULT_STOP_TIMEOUT: Duration = Duration::from_secs(10);
+/// Time on top of the stop timeout after which a kill no longer explains an exit
+const STOP_GRACE: Duration = Duration::from_secs(5);
 
 #[derive(Debug)]
 pub enum Event {
     SourceChanged(ImageName),
+    /// Stop or kill of a container was requested, by the user or by Ikki
+    ContainerKilled(String),
+    ContainerStarted(String),
+    ContainerDied {
+        service: ImageName,
+        id: String,
+        exit_code: i64,
+    },
+    ContainerUnhealthy(ImageName),
+    /// Backoff delay of a service restart elapsed
+    RestartService(ImageName),
     Shutdown,
 }
 
+/// Consecutive restarts of a service
+#[derive(Debug, Default)]
+struct Restarts {
+    count: u32,
+    last: Option<Instant>,
+}
+
 pub type EventReceiver = mpsc::Receiver<Event>;
 pub type EventSender = mpsc::Sender<Event>;
 
+/// How a service that exited is handled
+#[derive(Debug, PartialEq, Eq)]
+enum ExitAction {
+    /// The policy does not restart the service
+    Exit,
+    /// An `on-failure:N` policy ran out of its `N` restarts
+    GiveUp(u32),
+    /// Restart after `delay`, as restart `attempt` of the current series
+    Restart { attempt: u32, delay: Duration },
+}
+
+/// Decides how to handle a service exiting with `exit_code`, given the `restarts` of its current
+/// series and the time since the last one. A series ends once a restart ran long enough.
+fn exit_action(
+    policy: RestartPolicy,
+    exit_code: i64,
+    restarts: u32,
+    since_last_restart: Option<Duration>,
+) -> ExitAction {
+    let restarts = match since_last_restart {
+        Some(elapsed) if elapsed <= RESET_BACKOFF_AFTER => restarts,
+        _ => 0,
+    };
+
+    let restart = match policy {
+        RestartPolicy::No => false,
+        RestartPolicy::Always => true,
+        RestartPolicy::OnFailure(max) => exit_code != 0 && max.is_none_or(|max| restarts < max),
+    };
+
+    match policy {
+        _ if restart => ExitAction::Restart {
+            attempt: restarts + 1,
+            delay: INITIAL_BACKOFF
+                .saturating_mul(2u32.saturating_pow(restarts))
+                .min(MAX_BACKOFF),
+        },
+        RestartPolicy::OnFailure(Some(max)) if exit_code != 0 => ExitAction::GiveUp(max),
+        _ => ExitAction::Exit,
+    }
+}
+
 pub struct Supervisor {
     builder_handle: BuilderHandle,
     receiver: EventReceiver,
-    image_source_locations: ImageSourceLocations,
+    /// Schedules restarts after their backoff delay
+    sender: EventSender,
+    restarts: HashMap<ImageName, Restarts>,
+    /// Containers that were asked to stop and have not died or started since, with the time
+    /// of the last request
+    stopping: HashMap<String, Instant>,
 }
 
 impl Supervisor {
-    fn new(
-        builder: BuilderHandle,
-        image_source_locations: ImageSourceLocations,
-        receiver: EventReceiver,
-    ) -> Self {
+    fn new(builder: BuilderHandle, receiver: EventReceiver, sender: EventSender) -> Self {
         Self {
             builder_handle: builder,
             receiver,
-            image_source_locations,
+            sender,
+            restarts: HashMap::new(),
+            stopping: HashMap::new(),
+        }
+    }
+
+    /// Schedules a restart of `service` if its policy allows one, reporting crash loops.
+    fn handle_exit(&mut self, service: ImageName, exit_code: i64, policy: RestartPolicy) {
+        let restarts = self.restarts.entry(service.clone()).or_default();
+        let since_last_restart = restarts.last.map(|last| last.elapsed());
+
+        let delay = match exit_action(policy, exit_code, restarts.count, since_last_restart) {
+            ExitAction::Exit => {
+                println!("Service {} exited with code {}", service, exit_code);
+                return;
+            }
+            ExitAction::GiveUp(max) => {
+                println!(
+                    "Service {} exited with code {}, giving up after {} restart(s)",
+                    service, exit_code, max
+                );
+                return;
+            }
+            ExitAction::Restart { attempt, delay } => {
+                restarts.count = attempt;
+                delay
+            }
+        };
+
+        if restarts.count == CRASH_LOOP_RESTARTS {
+            println!(
+                "Service {} is crash looping, it exited {} times in a row",
+                service, restarts.count
+            );
         }
+        println!(
+            "Service {} exited with code {}, restarting in {}s",
+            service,
+            exit_code,
+            delay.as_secs()
+        );
+
+        let sender = self.sender.clone();
+        task::spawn(async move {
+            tokio::time::sleep(delay).await;
+            let _ = sender.send(Event::RestartService(service)).await;
+        });
     }
 }
 
+/// What the supervisor does in response to source changes and container events
 #[derive(Debug)]
 pub enum Mode {
-    BuildOnly,
-    Run,
+    /// Only rebuild changed images and their dependers, limited to the given image and its
+    /// dependencies if there is one
+    BuildOnly(Option<ImageName>),
+    /// Rebuild changed images and their dependers, recreate their containers and restart
+    /// the containers that depend on them at runtime. Exited containers are restarted
+    /// according to their restart policy, unless they were stopped on purpose, as Docker does.
+    Run(Services),
+}
+
+/// Service containers watched through Docker events
+#[derive(Debug)]
+pub struct Services {
+    pub docker: Docker,
+    pub project: String,
+    pub restart_policies: RestartPolicies,
+    pub stop_timeouts: StopTimeouts,
 }
 
 pub struct SupervisorHandle {
     sender: EventSender,
     fs_event_handle: FsEventListenerHandle,
+    docker_event_handle: Option<DockerEventListenerHandle>,
     handle: JoinHandle<()>,
 }
 
@@ -60,7 +202,15 @@ impl SupervisorHandle {
         mode: Mode,
     ) -> Self {
         let (sender, rx) = mpsc::channel::<Event>(10);
-        let supervisor = Supervisor::new(builder, image_source_locations.clone(), rx);
+        let supervisor = Supervisor::new(builder, rx, sender.clone());
+        let docker_event_handle = match &mode {
+            Mode::BuildOnly(_) => None,
+            Mode::Run(services) => Some(DockerEventListenerHandle::new(
+                services.docker.clone(),
+                services.project.clone(),
+                sender.clone(),
+            )),
+        };
         let handle = task::spawn(run_supervisor(supervisor, mode));
         let fs_event_handle = FsEventListenerHandle::new(image_source_locations, sender.clone());
 
@@ -68,6 +218,7 @@ impl SupervisorHandle {
             sender,
             handle,
             fs_event_handle,
+            docker_event_handle,
         }
     }
 
@@ -79,6 +230,11 @@ impl SupervisorHandle {
         debug!("shutting down fs event listener...");
         self.fs_event_handle.shutdown().await;
 
+        if let Some(docker_event_handle) = self.docker_event_handle {
+            debug!("shutting down Docker event listener...");
+            docker_event_handle.shutdown().await;
+        }
+
         debug!("shutting down supervisor loop...");
         self.handle
             .await
@@ -91,21 +247,198 @@ async fn run_supervisor(mut supervisor: Supervisor, mode: Mode) {
     while let Some(msg) = supervisor.receiver.recv().await {
         match msg {
             Event::Shutdown => {
-                if let Err(e) = supervisor.builder_handle.stop_all().await {
-                    println!("Ikki error: {}", e)
+                if let Mode::Run(_) = mode {
+                    if let Err(e) = supervisor.builder_handle.stop_all().await {
+                        println!("Ikki error: {}", e)
+                    }
                 }
+                // Pending restarts hold senders too, so the channel does not close by itself
+                break;
             }
             Event::SourceChanged(image_name) => {
-                if let Err(e) = supervisor.builder_handle.build(image_name.clone()).await {
-                    println!("Ikki error: {}", e)
+                let target = match &mode {
+                    Mode::BuildOnly(target) => target.clone(),
+                    Mode::Run(_) => None,
+                };
+                match supervisor
+                    .builder_handle
+                    .build(image_name.clone(), target)
+                    .await
+                {
+                    Err(IkkiError::BuildFailures(report)) => console::print_build_report(&report),
+                    Err(e) => println!("Ikki error: {}", e),
+                    Ok(()) => (),
                 }
 
-                if let Mode::Run = mode {
+                if let Mode::Run(_) = mode {
                     if let Err(e) = supervisor.builder_handle.run(image_name).await {
                         println!("Ikki error: {}", e)
                     }
                 }
             }
+            Event::ContainerKilled(id) => {
+                supervisor.stopping.insert(id, Instant::now());
+            }
+            // A container that survived a signal and got started again is no longer stopping
+            Event::ContainerStarted(id) => {
+                supervisor.stopping.remove(&id);
+            }
+            Event::ContainerDied {
+                service,
+                id,
+                exit_code,
+            } => {
+                let services = match &mode {
+                    Mode::Run(services) => services,
+                    Mode::BuildOnly(_) => continue,
+                };
+
+                // `docker stop` and `docker kill` are not restarted, like with Docker policies.
+                // A kill older than the stop timeout was a signal the container survived.
+                let stop_timeout = services
+                    .stop_timeouts
+                    .get(&service)
+                    .copied()
+                    .unwrap_or(DEFAULT_STOP_TIMEOUT);
+                if supervisor
+                    .stopping
+                    .remove(&id)
+                    .is_some_and(|killed| killed.elapsed() <= stop_timeout + STOP_GRACE)
+                {
+                    if let Ok(true) = docker::container_exited(&services.docker, &id).await {
+                        println!("Service {} was stopped, it is not restarted", service);
+                    }
+                    continue;
+                }
+
+                // Containers Ikki removed or restarted itself die too
+                match docker::container_exited(&services.docker, &id).await {
+                    Ok(true) => {
+                        let policy = services
+                            .restart_policies
+                            .get(&service)
+                            .copied()
+                            .unwrap_or_default();
+                        supervisor.handle_exit(service, exit_code, policy);
+                    }
+                    Ok(false) => debug!("ignoring exit of replaced container {}", id),
+                    Err(e) => println!("Ikki error: {}", e),
+                }
+            }
+            Event::ContainerUnhealthy(service) => {
+                println!("Service {} is unhealthy", service);
+            }
+            Event::RestartService(service) => {
+                if let Some(restarts) = supervisor.restarts.get_mut(&service) {
+                    restarts.last = Some(Instant::now());
+                }
+                if let Err(e) = supervisor.builder_handle.restart(service).await {
+                    println!("Ikki error: {}", e)
+                }
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exit_without_restart_policy() {
+        assert_eq!(exit_action(RestartPolicy::No, 1, 0, None), ExitAction::Exit);
+    }
+
+    #[test]
+    fn on_failure_ignores_successful_exit() {
+        assert_eq!(
+            exit_action(RestartPolicy::OnFailure(None), 0, 0, None),
+            ExitAction::Exit
+        );
+        assert_eq!(
+            exit_action(RestartPolicy::OnFailure(None), 1, 0, None),
+            ExitAction::Restart {
+                attempt: 1,
+                delay: INITIAL_BACKOFF
+            }
+        );
+    }
+
+    #[test]
+    fn always_restarts_successful_exit() {
+        assert_eq!(
+            exit_action(RestartPolicy::Always, 0, 0, None),
+            ExitAction::Restart {
+                attempt: 1,
+                delay: INITIAL_BACKOFF
+            }
+        );
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        let recently = Some(Duration::from_secs(1));
+        let delays: Vec<Duration> = (0..7)
+            .map(
+                |restarts| match exit_action(RestartPolicy::Always, 1, restarts, recently) {
+                    ExitAction::Restart { delay, .. } => delay,
+                    other => panic!("expected a restart, got {:?}", other),
+                },
+            )
+            .collect();
+        let secs: Vec<u64> = delays.iter().map(Duration::as_secs).collect();
+        assert_eq!(secs, vec![1, 2, 4, 8, 16, 30, 30]);
+
+        assert_eq!(
+            exit_action(RestartPolicy::Always, 1, u32::MAX - 1, recently),
+            ExitAction::Restart {
+                attempt: u32::MAX,
+                delay: MAX_BACKOFF
+            }
+        );
+    }
+
+    #[test]
+    fn series_resets_after_long_run() {
+        let long_ago = Some(RESET_BACKOFF_AFTER + Duration::from_secs(1));
+        assert_eq!(
+            exit_action(RestartPolicy::Always, 1, 4, long_ago),
+            ExitAction::Restart {
+                attempt: 1,
+                delay: INITIAL_BACKOFF
+            }
+        );
+        assert_eq!(
+            exit_action(RestartPolicy::OnFailure(Some(3)), 1, 3, long_ago),
+            ExitAction::Restart {
+                attempt: 1,
+                delay: INITIAL_BACKOFF
+            }
+        );
+    }
+
+    #[test]
+    fn on_failure_gives_up_after_max() {
+        let recently = Some(Duration::from_secs(1));
+        assert_eq!(
+            exit_action(RestartPolicy::OnFailure(Some(3)), 1, 2, recently),
+            ExitAction::Restart {
+                attempt: 3,
+                delay: Duration::from_secs(4)
+            }
+        );
+        assert_eq!(
+            exit_action(RestartPolicy::OnFailure(Some(3)), 1, 3, recently),
+            ExitAction::GiveUp(3)
+        );
+    }
+
+    #[test]
+    fn crash_loop_is_reached_by_restart_attempt() {
+        let recently = Some(Duration::from_secs(1));
+        assert!(matches!(
+            exit_action(RestartPolicy::Always, 1, CRASH_LOOP_RESTARTS - 1, recently),
+            ExitAction::Restart { attempt, .. } if attempt == CRASH_LOOP_RESTARTS
+        ));
+    }
+}
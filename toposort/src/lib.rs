@@ -1,95 +1,340 @@
-use std::collections::{HashSet, VecDeque};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::hash::Hash;
 
-use multimap::MultiMap;
+/// Directed acyclic graph stored as adjacency lists over node indices.
+///
+/// Nodes are indexed in the order they are first added, which makes every
+/// traversal deterministic regardless of hashing.
+#[derive(Debug, Clone)]
+pub struct Dag<Node: Eq + Hash> {
+    nodes: Vec<Node>,
+    indices: HashMap<Node, usize>,
+    successors: Vec<Vec<usize>>,
+    predecessors: Vec<Vec<usize>>,
+    edges: HashSet<(usize, usize)>,
+}
 
-#[derive(Debug)]
-pub struct Dag<Node: Eq + std::hash::Hash> {
-    precedence: MultiMap<Node, Node>,
+/// A cycle that makes topological sorting impossible.
+///
+/// `path` starts and ends with the same node and every node in it must come
+/// before the next one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CycleError<Node> {
+    pub path: Vec<Node>,
 }
 
+impl<Node: fmt::Display> fmt::Display for CycleError<Node> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let path = self
+            .path
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(" -> ");
+        write!(f, "cycle detected: {}", path)
+    }
+}
+
+impl<Node: fmt::Debug + fmt::Display> std::error::Error for CycleError<Node> {}
+
 pub trait Toposort<Node> {
-    fn toposort(&self) -> Option<Vec<Vec<Node>>>;
+    fn toposort(&self) -> Result<Vec<Vec<Node>>, CycleError<Node>>;
+}
+
+impl<Node> Default for Dag<Node>
+where
+    Node: Eq + Hash + Clone,
+{
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl<Node> Dag<Node>
 where
-    Node: Eq + std::hash::Hash + Clone,
+    Node: Eq + Hash + Clone,
 {
     pub fn new() -> Self {
         Self {
-            precedence: MultiMap::new(),
+            nodes: vec![],
+            indices: HashMap::new(),
+            successors: vec![],
+            predecessors: vec![],
+            edges: HashSet::new(),
         }
     }
 
+    /// Registers `node` if it is not part of the graph yet and returns its index.
+    pub fn add_node(&mut self, node: Node) -> usize {
+        if let Some(&index) = self.indices.get(&node) {
+            return index;
+        }
+
+        let index = self.nodes.len();
+        self.nodes.push(node.clone());
+        self.indices.insert(node, index);
+        self.successors.push(vec![]);
+        self.predecessors.push(vec![]);
+        index
+    }
+
+    /// Adds an edge requiring `first` to come before `second`, registering both nodes.
     pub fn before(&mut self, first: Node, second: Node) {
-        self.precedence.insert(first, second)
+        let from = self.add_node(first);
+        let to = self.add_node(second);
+
+        if self.edges.insert((from, to)) {
+            self.successors[from].push(to);
+            self.predecessors[to].push(from);
+        }
+    }
+
+    pub fn contains(&self, node: &Node) -> bool {
+        self.indices.contains_key(node)
     }
 
-    fn has_parent(&self, node: &Node) -> bool {
-        self.precedence
-            .iter_all()
-            .any(|(_, children)| children.contains(node))
+    /// All nodes in the order they were added.
+    pub fn nodes(&self) -> impl Iterator<Item = &Node> {
+        self.nodes.iter()
     }
 
-    fn has_parents_except(
-        &self,
-        node: &Node,
-        except: &Node,
-        removed_edges: &HashSet<&Node>,
-    ) -> bool {
-        self.precedence.iter_all().any(|(parent, children)| {
-            parent != except && !removed_edges.contains(&parent) && children.contains(node)
-        })
+    pub fn len(&self) -> usize {
+        self.nodes.len()
     }
 
-    fn orphans(&self) -> VecDeque<&Node> {
-        let mut no_incoming_edge = VecDeque::new();
-        for node in self.precedence.keys() {
-            if !self.has_parent(node) {
-                no_incoming_edge.push_back(node);
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Indices reachable from `start` by repeatedly following `edges`, excluding `start`.
+    fn reachable(&self, start: usize, edges: &[Vec<usize>]) -> Vec<usize> {
+        let mut seen = vec![false; self.nodes.len()];
+        let mut stack = edges[start].clone();
+        let mut reached = vec![];
+
+        while let Some(index) = stack.pop() {
+            if seen[index] {
+                continue;
+            }
+            seen[index] = true;
+            reached.push(index);
+            stack.extend(edges[index].iter().filter(|&&next| !seen[next]));
+        }
+
+        reached.sort_unstable();
+        reached
+    }
+
+    fn to_nodes(&self, indices: Vec<usize>) -> Vec<Node> {
+        indices
+            .into_iter()
+            .map(|index| self.nodes[index].clone())
+            .collect()
+    }
+
+    /// Nodes with an edge to `node`.
+    pub fn predecessors(&self, node: &Node) -> Vec<&Node> {
+        match self.indices.get(node) {
+            Some(&index) => self.predecessors[index]
+                .iter()
+                .map(|&predecessor| &self.nodes[predecessor])
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    /// Nodes with an edge from `node`.
+    pub fn successors(&self, node: &Node) -> Vec<&Node> {
+        match self.indices.get(node) {
+            Some(&index) => self.successors[index]
+                .iter()
+                .map(|&successor| &self.nodes[successor])
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    /// Number of nodes on the longest path starting at each node, the node included.
+    ///
+    /// Nodes with longer paths sit on the critical path and are worth starting first.
+    pub fn critical_path_lengths(&self) -> Result<HashMap<Node, usize>, CycleError<Node>> {
+        let order = self.toposort()?;
+        let mut lengths = vec![1; self.nodes.len()];
+
+        for node in order.iter().rev().flatten() {
+            let index = self.indices[node];
+            lengths[index] += self.successors[index]
+                .iter()
+                .map(|&successor| lengths[successor])
+                .max()
+                .unwrap_or(0);
+        }
+
+        Ok(self.nodes.iter().cloned().zip(lengths).collect())
+    }
+
+    /// Every node that has to come before `node`, in the order nodes were added.
+    pub fn ancestors(&self, node: &Node) -> Vec<Node> {
+        match self.indices.get(node) {
+            Some(&index) => self.to_nodes(self.reachable(index, &self.predecessors)),
+            None => vec![],
+        }
+    }
+
+    /// Every node that has to come after `node`, in the order nodes were added.
+    pub fn descendants(&self, node: &Node) -> Vec<Node> {
+        match self.indices.get(node) {
+            Some(&index) => self.to_nodes(self.reachable(index, &self.successors)),
+            None => vec![],
+        }
+    }
+
+    /// Graph with only the given nodes and the edges between them.
+    ///
+    /// Nodes that are not part of this graph are ignored.
+    pub fn induced_subgraph<'a, I>(&self, nodes: I) -> Dag<Node>
+    where
+        I: IntoIterator<Item = &'a Node>,
+        Node: 'a,
+    {
+        let mut keep = vec![false; self.nodes.len()];
+        for node in nodes {
+            if let Some(&index) = self.indices.get(node) {
+                keep[index] = true;
+            }
+        }
+
+        let mut subgraph = Dag::new();
+        for (index, node) in self.nodes.iter().enumerate() {
+            if keep[index] {
+                subgraph.add_node(node.clone());
+            }
+        }
+        for (index, successors) in self.successors.iter().enumerate() {
+            if !keep[index] {
+                continue;
+            }
+            for &successor in successors.iter().filter(|&&successor| keep[successor]) {
+                subgraph.before(self.nodes[index].clone(), self.nodes[successor].clone());
+            }
+        }
+        subgraph
+    }
+
+    /// Graph with the same ordering constraints but without edges implied by other edges.
+    ///
+    /// Fails if the graph has a cycle, since its transitive reduction is not unique.
+    pub fn transitive_reduction(&self) -> Result<Dag<Node>, CycleError<Node>> {
+        self.toposort()?;
+
+        let mut reduced = Dag::new();
+        for node in self.nodes.iter() {
+            reduced.add_node(node.clone());
+        }
+
+        for (index, successors) in self.successors.iter().enumerate() {
+            let mut implied = vec![false; self.nodes.len()];
+            for &successor in successors.iter() {
+                for reachable in self.reachable(successor, &self.successors) {
+                    implied[reachable] = true;
+                }
+            }
+
+            for &successor in successors.iter().filter(|&&successor| !implied[successor]) {
+                reduced.before(self.nodes[index].clone(), self.nodes[successor].clone());
+            }
+        }
+
+        Ok(reduced)
+    }
+
+    fn find_cycle(&self, in_degrees: &[usize]) -> CycleError<Node> {
+        // Every node left with incoming edges after the sort has at least one
+        // predecessor in the same situation, so walking predecessors backwards
+        // must eventually revisit a node.
+        let unvisited = |index: &usize| in_degrees[*index] > 0;
+
+        let start = (0..self.nodes.len())
+            .find(unvisited)
+            .expect("sort with a cycle must leave unvisited nodes");
+
+        let mut path = vec![start];
+        let mut current = start;
+
+        loop {
+            let parent = self.predecessors[current]
+                .iter()
+                .copied()
+                .find(unvisited)
+                .expect("unvisited node must have an unvisited predecessor");
+
+            if let Some(position) = path.iter().position(|&index| index == parent) {
+                let mut cycle = vec![parent];
+                cycle.extend(path[position + 1..].iter().rev());
+                cycle.push(parent);
+                let path = cycle
+                    .into_iter()
+                    .map(|index| self.nodes[index].clone())
+                    .collect();
+                return CycleError { path };
             }
+
+            path.push(parent);
+            current = parent;
         }
-        no_incoming_edge
     }
 }
 
 impl<Node> Toposort<Node> for Dag<Node>
 where
-    Node: Eq + std::hash::Hash + Clone,
+    Node: Eq + Hash + Clone,
 {
-    fn toposort(&self) -> Option<Vec<Vec<Node>>> {
+    /// Kahn's algorithm grouped into chunks of nodes that do not depend on each other.
+    ///
+    /// Runs in O(V + E). The first chunk keeps the order in which nodes were added and
+    /// later chunks keep the order in which their nodes became ready.
+    fn toposort(&self) -> Result<Vec<Vec<Node>>, CycleError<Node>> {
+        let mut in_degrees: Vec<usize> = self.predecessors.iter().map(Vec::len).collect();
+        let mut ready: Vec<usize> = (0..self.nodes.len())
+            .filter(|&index| in_degrees[index] == 0)
+            .collect();
+
         let mut order = vec![];
-        let mut orphans = self.orphans();
-        let mut removed_edges = HashSet::new();
-
-        while !orphans.is_empty() {
-            let mut sorted = Vec::with_capacity(orphans.len());
-            for _ in 0..orphans.len() {
-                let node = orphans.pop_front().unwrap();
-                if let Some(children) = self.precedence.get_vec(node) {
-                    for child in children {
-                        if !self.has_parents_except(child, node, &removed_edges) {
-                            orphans.push_back(child);
-                        }
+        let mut visited = 0;
+
+        while !ready.is_empty() {
+            let mut next = vec![];
+            for &index in ready.iter() {
+                for &successor in self.successors[index].iter() {
+                    in_degrees[successor] -= 1;
+                    if in_degrees[successor] == 0 {
+                        next.push(successor);
                     }
                 }
-                removed_edges.insert(node);
-                sorted.push(node.clone());
             }
-            order.push(sorted)
+
+            visited += ready.len();
+            order.push(
+                ready
+                    .into_iter()
+                    .map(|index| self.nodes[index].clone())
+                    .collect(),
+            );
+            ready = next;
         }
 
-        if removed_edges.len() < self.precedence.keys().len() {
-            return None;
+        if visited < self.nodes.len() {
+            return Err(self.find_cycle(&in_degrees));
         }
 
-        Some(order)
+        Ok(order)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{Dag, Toposort};
+    use super::{CycleError, Dag, Toposort};
 
     #[test]
     fn normal_sort() {
@@ -98,7 +343,7 @@ mod tests {
         dag.before("b", "c");
         let order = dag.toposort();
         let expected = vec![vec!["a"], vec!["b"], vec!["c"]];
-        assert_eq!(order, Some(expected));
+        assert_eq!(order, Ok(expected));
     }
 
     #[test]
@@ -107,7 +352,25 @@ mod tests {
         dag.before("a", "b");
         dag.before("b", "a");
         let order = dag.toposort();
-        assert!(order.is_none());
+        assert!(order.is_err());
+    }
+
+    #[test]
+    fn cycle_path() {
+        let mut dag = Dag::new();
+        dag.before("x", "a");
+        dag.before("a", "b");
+        dag.before("b", "c");
+        dag.before("c", "a");
+        let cycle = dag.toposort().unwrap_err();
+
+        assert_eq!(cycle.path, vec!["a", "b", "c", "a"]);
+
+        let message = CycleError {
+            path: vec!["a", "b", "a"],
+        }
+        .to_string();
+        assert_eq!(message, "cycle detected: a -> b -> a");
     }
 
     #[test]
@@ -117,7 +380,7 @@ mod tests {
         dag.before("c", "b");
         dag.before("d", "b");
         let order = dag.toposort();
-        assert!(matches!(order, Some(_)));
+        assert!(order.is_ok());
         let mut order = order.unwrap();
         for suborder in order.iter_mut() {
             suborder.sort();
@@ -132,7 +395,7 @@ mod tests {
         dag.before("a", "b");
         dag.before("a", "c");
         let order = dag.toposort();
-        assert!(matches!(order, Some(_)));
+        assert!(order.is_ok());
         let mut order = order.unwrap();
         for suborder in order.iter_mut() {
             suborder.sort();
@@ -140,4 +403,122 @@ mod tests {
         let expected = vec![vec!["a"], vec!["b", "c"]];
         assert_eq!(order, expected);
     }
+
+    #[test]
+    fn isolated_and_leaf_only_nodes() {
+        let mut dag = Dag::new();
+        dag.add_node("e");
+        dag.before("a", "b");
+        dag.before("c", "d");
+        dag.add_node("a");
+        let order = dag.toposort();
+        let expected = vec![vec!["e", "a", "c"], vec!["b", "d"]];
+        assert_eq!(order, Ok(expected));
+    }
+
+    #[test]
+    fn duplicate_edges() {
+        let mut dag = Dag::new();
+        dag.before("a", "b");
+        dag.before("a", "b");
+        let order = dag.toposort();
+        let expected = vec![vec!["a"], vec!["b"]];
+        assert_eq!(order, Ok(expected));
+    }
+
+    #[test]
+    fn deterministic_chunks() {
+        let build = || {
+            let mut dag = Dag::new();
+            for i in 0..100 {
+                dag.before(i, 100 + i % 7);
+            }
+            dag.toposort().unwrap()
+        };
+        let first = build();
+        for _ in 0..10 {
+            assert_eq!(build(), first);
+        }
+        assert_eq!(first[0], (0..100).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn long_chain() {
+        let mut dag = Dag::new();
+        for i in 0..100_000 {
+            dag.before(i, i + 1);
+        }
+        let order = dag.toposort().unwrap();
+        assert_eq!(order.len(), 100_001);
+    }
+
+    #[test]
+    fn ancestors_and_descendants() {
+        let mut dag = Dag::new();
+        dag.before("a", "b");
+        dag.before("b", "c");
+        dag.before("x", "c");
+        dag.before("c", "d");
+        dag.add_node("e");
+
+        assert_eq!(dag.ancestors(&"c"), vec!["a", "b", "x"]);
+        assert_eq!(dag.descendants(&"b"), vec!["c", "d"]);
+        assert!(dag.ancestors(&"e").is_empty());
+        assert!(dag.descendants(&"missing").is_empty());
+    }
+
+    #[test]
+    fn induced_subgraph() {
+        let mut dag = Dag::new();
+        dag.before("a", "b");
+        dag.before("b", "c");
+        dag.before("x", "c");
+        dag.before("a", "y");
+
+        let subgraph = dag.induced_subgraph(&["c", "a", "x", "missing"]);
+        assert_eq!(subgraph.nodes().collect::<Vec<_>>(), vec![&"a", &"c", &"x"]);
+
+        let order = subgraph.toposort();
+        let expected = vec![vec!["a", "x"], vec!["c"]];
+        assert_eq!(order, Ok(expected));
+    }
+
+    #[test]
+    fn transitive_reduction() {
+        let mut dag = Dag::new();
+        dag.before("a", "b");
+        dag.before("b", "c");
+        dag.before("a", "c");
+        dag.before("c", "d");
+        dag.before("a", "d");
+
+        let reduced = dag.transitive_reduction().unwrap();
+        assert_eq!(reduced.descendants(&"a"), vec!["b", "c", "d"]);
+        assert_eq!(reduced.edges.len(), 3);
+        assert_eq!(reduced.toposort(), dag.toposort());
+
+        let mut cyclic = Dag::new();
+        cyclic.before("a", "b");
+        cyclic.before("b", "a");
+        assert!(cyclic.transitive_reduction().is_err());
+    }
+
+    #[test]
+    fn critical_path_lengths() {
+        let mut dag = Dag::new();
+        dag.before("a", "b");
+        dag.before("b", "c");
+        dag.before("x", "c");
+        dag.add_node("y");
+
+        let lengths = dag.critical_path_lengths().unwrap();
+        assert_eq!(lengths["a"], 3);
+        assert_eq!(lengths["b"], 2);
+        assert_eq!(lengths["x"], 2);
+        assert_eq!(lengths["c"], 1);
+        assert_eq!(lengths["y"], 1);
+
+        assert_eq!(dag.predecessors(&"c"), vec![&"b", &"x"]);
+        assert_eq!(dag.successors(&"a"), vec![&"b"]);
+    }
 }